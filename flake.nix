@@ -35,6 +35,8 @@
 
           buildInputs = [ sqlite ];
 
+          buildFeatures = [ "server" ];
+
           postInstall = ''
             substituteInPlace command-not-found.sh \
               --subst-var out
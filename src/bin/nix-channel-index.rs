@@ -7,7 +7,8 @@ use std::process;
 
 use clap::Parser;
 use futures::{future, StreamExt};
-use nix_index::files::{FileNode, FileType};
+use nix_index::database::{Reader, Resolver};
+use nix_index::files::{FileNode, FileType, Resolved};
 use nix_index::hydra::Fetcher;
 use nix_index::listings;
 use nix_index::{errors::*, CACHE_URL};
@@ -64,10 +65,23 @@ async fn update_index(args: &Args) -> Result<()> {
         None => vec![None],
     };
 
+    // Open the database before fetching anything, so that a missing database fails early
+    let database = match &args.database {
+        Some(database) => {
+            let path = database.join("files");
+            match Reader::open(&path) {
+                Ok(reader) => Some((path, reader)),
+                Err(e) => return Err(Error::ReadDatabase { path, source: e }),
+            }
+        }
+        None => None,
+    };
+
     eprint!("+ querying available packages");
     let (files, watch) = listings::fetch(
         &fetcher,
         args.jobs,
+        false,
         &args.nixpkgs,
         systems,
         &args.extra_scopes,
@@ -98,6 +112,11 @@ async fn update_index(args: &Args) -> Result<()> {
 
     let mut files = files.filter_map(future::ready);
 
+    // Symlinks in `/bin` that point to other store paths, which are resolved with the database
+    // once all packages are known. Without a database, they are assumed to be programs.
+    let mut external = Vec::new();
+    let mut store_dir = String::new();
+
     eprint!("+ generating index");
     eprint!("\r");
 
@@ -108,14 +127,20 @@ async fn update_index(args: &Args) -> Result<()> {
             // skip dependencies
             continue;
         }
+        let root = path.as_str();
+        store_dir = path.store_dir().into_owned();
 
         for item in files.to_list(&[]) {
-            if let FileNode::Symlink { target: _ } // FIXME: should probably check if the target is executable...
+            if let FileNode::Symlink { target: _ }
             | FileNode::Regular {
                 size: _,
                 executable: true,
             } = item.node
             {
+                let resolved = match item.node {
+                    FileNode::Symlink { .. } => files.resolve(root.as_bytes(), &item.path),
+                    _ => Resolved::Found(item.node.clone()),
+                };
                 let path = PathBuf::from(OsString::from_vec(item.path));
 
                 if let Ok(binary) = path.strip_prefix("/bin") {
@@ -127,12 +152,19 @@ async fn update_index(args: &Args) -> Result<()> {
                         continue;
                     }
 
-                    connection
-                        .execute(
-                            "insert or replace into Programs(name, system, package) values (?, ?, ?)",
-                            (binary, system, attr),
-                        )
-                        .map_err(|e| Error::CreateDatabase { path: args.output.clone(), source: Box::new(e) })?;
+                    match resolved {
+                        Resolved::Found(FileNode::Regular {
+                            executable: true, ..
+                        }) => {}
+                        Resolved::External(target) if database.is_some() => {
+                            external.push((target, (binary, system, attr)));
+                            continue;
+                        }
+                        Resolved::External(_) => {}
+                        // dangling symlinks and symlinks to other files are not programs
+                        _ => continue,
+                    }
+                    add_program(&connection, args, (binary, system, attr))?;
                 }
 
                 if let Ok(debuginfo) = path.strip_prefix("/lib/debug/.build-id") {
@@ -161,6 +193,27 @@ async fn update_index(args: &Args) -> Result<()> {
     }
     eprintln!();
 
+    if let Some((path, reader)) = database {
+        eprintln!(
+            "+ resolving {} symlinks to other store paths",
+            external.len()
+        );
+        let (targets, programs): (Vec<_>, Vec<_>) = external.into_iter().unzip();
+        let resolved = Resolver::new(reader, &store_dir)
+            .resolve_all(targets)
+            .map_err(|e| Error::ReadDatabase { path, source: e })?;
+        for (resolved, program) in resolved.into_iter().zip(programs) {
+            // targets outside the database cannot be checked, so they are kept
+            if let Resolved::Found(FileNode::Regular {
+                executable: true, ..
+            })
+            | Resolved::External(_) = resolved
+            {
+                add_program(&connection, args, program)?;
+            }
+        }
+    }
+
     eprint!("+ dumping index");
 
     connection
@@ -180,6 +233,25 @@ async fn update_index(args: &Args) -> Result<()> {
     Ok(())
 }
 
+/// Adds a program, given by its name, system and package, to the programs database.
+#[allow(clippy::result_large_err)]
+fn add_program(
+    connection: &Connection,
+    args: &Args,
+    program: (String, Option<String>, String),
+) -> Result<()> {
+    connection
+        .execute(
+            "insert or replace into Programs(name, system, package) values (?, ?, ?)",
+            program,
+        )
+        .map_err(|e| Error::CreateDatabase {
+            path: args.output.clone(),
+            source: Box::new(e),
+        })?;
+    Ok(())
+}
+
 #[derive(Debug, Parser)]
 #[clap(author, about, version)]
 struct Args {
@@ -199,6 +271,12 @@ struct Args {
     #[clap(short, long, default_value = "debug.sqlite")]
     debug_output: PathBuf,
 
+    /// Directory of a nix-index database, which is used to check whether symlinks in `/bin`
+    /// that point to other store paths lead to an executable. Without a database, such symlinks
+    /// are assumed to be programs.
+    #[clap(long = "db")]
+    database: Option<PathBuf>,
+
     /// Systems to include in generated database
     #[clap(short = 's', long = "platform")]
     systems: Option<Vec<String>>,
@@ -1,24 +1,30 @@
 //! Tool for generating a nix-index database.
 use std::ffi::OsStr;
-use std::fs::{self, File};
+use std::fs::{self, File, TryLockError};
 use std::io::{self, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use futures::future::Either;
 use futures::{future, StreamExt};
-use nix_index::database::Writer;
+use nix_index::database::{self, Metadata, PackageOrder, Reader, Writer};
+use nix_index::diff::{self, FileChange, PackageChanges};
 use nix_index::errors::*;
-use nix_index::files::FileTree;
+use nix_index::files::{FileNode, FileTree};
+use nix_index::format::Bytes;
 use nix_index::hydra::Fetcher;
 use nix_index::listings::{self, try_load_paths_cache};
 use nix_index::package::StorePath;
 use nix_index::CACHE_URL;
+use nix_index::{nixpkgs, sqlite};
+use rusqlite::Connection;
 use separator::Separatable;
 
 /// The main function of this module: creates a new nix-index database.
 async fn update_index(args: &Args) -> Result<()> {
+    let _lock = lock_database(&args.database)?;
+
     // first try to load the paths.cache if requested, otherwise query
     // the packages normally. Also fall back to normal querying if the paths.cache
     // fails to load.
@@ -29,20 +35,41 @@ async fn update_index(args: &Args) -> Result<()> {
         None
     };
 
+    // only reuse the existing database if we don't load the paths from the cache anyway
+    let previous = if args.incremental && cached.is_none() {
+        open_previous_database(args)
+    } else {
+        None
+    };
+
     eprintln!("+ querying available packages");
     let fetcher = Fetcher::new(CACHE_URL.to_string()).map_err(Error::ParseProxy)?;
-    let (files, watch) = match cached {
-        Some((f, w)) => (Either::Left(f), w),
-        None => {
+    let (files, watch) = match (cached, previous) {
+        (Some((f, w)), _) => (Either::Left(f), w),
+        (None, Some(previous)) => {
+            let (f, w) = listings::fetch_incremental(
+                &fetcher,
+                args.jobs,
+                args.writer.reproducible,
+                &args.nixpkgs,
+                vec![args.system.as_deref()],
+                &args.extra_scopes,
+                args.show_trace,
+                previous,
+            )?;
+            (Either::Right(Either::Left(f)), w)
+        }
+        (None, None) => {
             let (f, w) = listings::fetch(
                 &fetcher,
                 args.jobs,
+                args.writer.reproducible,
                 &args.nixpkgs,
                 vec![args.system.as_deref()],
                 &args.extra_scopes,
                 args.show_trace,
             )?;
-            (Either::Right(f), w)
+            (Either::Right(Either::Right(f)), w)
         }
     };
 
@@ -76,17 +103,21 @@ async fn update_index(args: &Args) -> Result<()> {
         eprint!(" (filtering by `{}`)", args.filter_prefix);
     }
     eprint!("\r");
-    fs::create_dir_all(&args.database).map_err(|e| Error::CreateDatabaseDir {
-        path: args.database.clone(),
-        source: e,
-    })?;
-    let mut db =
-        Writer::create(args.database.join("files"), args.compression_level).map_err(|e| {
-            Error::CreateDatabase {
-                path: args.database.clone(),
-                source: Box::new(e),
-            }
-        })?;
+    let mut db = create_database(&args.database, &args.writer)?;
+    db.set_indexed_directories(
+        args.basename_index
+            .iter()
+            .filter(|dir| !dir.is_empty())
+            .cloned()
+            .collect(),
+    );
+    db.set_metadata(Metadata {
+        nixpkgs: Some(args.nixpkgs.clone()),
+        nixpkgs_version: nixpkgs::query_version(&args.nixpkgs),
+        extra_scopes: args.extra_scopes.clone(),
+        filter_prefix: args.filter_prefix.clone(),
+        ..Metadata::default()
+    });
 
     let mut results: Vec<(StorePath, String, FileTree)> = Vec::new();
     while let Some(entry) = files.next().await {
@@ -124,6 +155,294 @@ async fn update_index(args: &Args) -> Result<()> {
     Ok(())
 }
 
+/// Creates the given database directory if necessary and locks it, so that concurrent runs of
+/// nix-index cannot write to the same database.
+///
+/// The lock is held until the returned file is closed. If another process holds the lock,
+/// this waits until that process is done.
+#[allow(clippy::result_large_err)]
+fn lock_database(database: &Path) -> Result<File> {
+    fs::create_dir_all(database).map_err(|e| Error::CreateDatabaseDir {
+        path: database.to_path_buf(),
+        source: e,
+    })?;
+
+    let lock_file = database.join("lock");
+    let lock_error = |e| Error::LockDatabase {
+        path: lock_file.clone(),
+        source: e,
+    };
+    let lock = File::create(&lock_file).map_err(lock_error)?;
+    match lock.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            eprintln!("+ waiting for another nix-index process to finish writing the database");
+            lock.lock().map_err(lock_error)?;
+        }
+        Err(TryLockError::Error(e)) => return Err(lock_error(e)),
+    }
+    Ok(lock)
+}
+
+/// Creates a new database in the given directory, which replaces the existing one once the
+/// database is finished.
+///
+/// The directory should have been locked with `lock_database` before.
+#[allow(clippy::result_large_err)]
+fn create_database(database: &Path, args: &WriterArgs) -> Result<Writer> {
+    let mut writer =
+        Writer::create(database.join("files"), args.level).map_err(|e| Error::CreateDatabase {
+            path: database.to_path_buf(),
+            source: Box::new(e),
+        })?;
+    writer.set_dictionary_size(args.dictionary_size);
+    writer.set_long_distance_matching(args.long);
+    writer.set_package_order(match args.order {
+        Some(order) => order.into(),
+        None if args.reproducible => PackageOrder::Hash,
+        None => PackageOrder::Added,
+    });
+    if args.reproducible {
+        let created = std::env::var("SOURCE_DATE_EPOCH")
+            .ok()
+            .and_then(|epoch| epoch.parse().ok())
+            .unwrap_or(0);
+        writer.set_created(created);
+    }
+    Ok(writer)
+}
+
+/// Opens a database given either as a directory (like `--db`) or as the database file itself.
+#[allow(clippy::result_large_err)]
+fn open_database(path: &Path) -> Result<Reader> {
+    let file = if path.is_dir() {
+        path.join("files")
+    } else {
+        path.to_path_buf()
+    };
+    Reader::open(&file).map_err(|e| Error::ReadDatabase {
+        path: file,
+        source: e,
+    })
+}
+
+/// Merges several databases into a single one.
+#[allow(clippy::result_large_err)]
+fn merge_databases(args: &MergeArgs) -> Result<()> {
+    let _lock = lock_database(&args.database)?;
+    let inputs = args
+        .inputs
+        .iter()
+        .map(|path| open_database(path))
+        .collect::<Result<Vec<_>>>()?;
+
+    eprintln!("+ merging {} databases", inputs.len());
+    let mut db = create_database(&args.database, &args.writer)?;
+    let packages =
+        database::merge(&inputs, &mut db).map_err(|e| Error::MergeDatabases { source: e })?;
+
+    let index_size = db.finish().map_err(|e| Error::WriteDatabase {
+        path: args.database.clone(),
+        source: e,
+    })?;
+    eprintln!(
+        "+ wrote index of {} packages and {} bytes",
+        packages.separated_string(),
+        index_size.separated_string()
+    );
+
+    Ok(())
+}
+
+/// Checks the integrity of a database.
+#[allow(clippy::result_large_err)]
+fn verify_database(args: &VerifyArgs) -> Result<()> {
+    let db = open_database(&args.database)?;
+    let verification = db.verify().map_err(|e| Error::VerifyDatabase {
+        path: args.database.clone(),
+        source: e,
+    })?;
+    eprintln!(
+        "+ database is valid: {} frames, {} packages and {} entries",
+        verification.frames.separated_string(),
+        verification.packages.separated_string(),
+        verification.entries.separated_string()
+    );
+    Ok(())
+}
+
+/// Exports a database to SQLite.
+#[allow(clippy::result_large_err)]
+fn export_sqlite(args: &ExportSqliteArgs) -> Result<()> {
+    let db = open_database(&args.database)?;
+    let create_error = |e: Box<dyn std::error::Error>| Error::CreateDatabase {
+        path: args.output.clone(),
+        source: e,
+    };
+
+    // start with an empty database, since the tables are created by the export
+    match fs::remove_file(&args.output) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(create_error(Box::new(e))),
+        _ => {}
+    }
+    let mut connection = Connection::open(&args.output).map_err(|e| create_error(Box::new(e)))?;
+
+    eprintln!("+ exporting database to {}", args.output.display());
+    let (packages, entries) =
+        sqlite::export(&db, &mut connection).map_err(|e| Error::ExportSqlite {
+            path: args.output.clone(),
+            source: e,
+        })?;
+    eprintln!(
+        "+ exported {} packages and {} files",
+        packages.separated_string(),
+        entries.separated_string()
+    );
+    Ok(())
+}
+
+/// Returns the type and size of a file node, in the same form as printed by nix-locate.
+fn node_summary(node: &FileNode<()>) -> (&'static str, u64) {
+    match *node {
+        FileNode::Regular { executable, size } => (if executable { "x" } else { "r" }, size),
+        FileNode::Directory { size, .. } => ("d", size),
+        FileNode::Symlink { .. } => ("s", 0),
+    }
+}
+
+/// Prints the files that changed between two databases.
+#[allow(clippy::result_large_err)]
+fn diff_databases(args: &DiffArgs) -> Result<()> {
+    let old = open_database(&args.old)?;
+    let new = open_database(&args.new)?;
+    let packages = diff::diff(&old, &new).map_err(|e| Error::DiffDatabases { source: e })?;
+
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    let result = if args.json {
+        write_diff_json(&mut out, &packages)
+    } else {
+        write_diff(&mut out, &packages)
+    };
+    match result.and_then(|_| out.flush()) {
+        // the reader of the output went away (e.g. `| head`), which is not an error
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        Err(e) => Err(Error::WriteDiff { source: e }),
+        Ok(()) => Ok(()),
+    }
+}
+
+/// Writes the diff in a human readable form, grouped by package.
+fn write_diff<W: Write>(out: &mut W, packages: &[PackageChanges]) -> io::Result<()> {
+    for package in packages {
+        let origin = &package.origin;
+        write!(out, "{}.{}", origin.attr, origin.output)?;
+        if let Some(system) = &origin.system {
+            write!(out, " ({})", system)?;
+        }
+        match (&package.old, &package.new) {
+            (Some(old), Some(new)) => writeln!(out, ": {} -> {}", old.as_str(), new.as_str())?,
+            (None, Some(new)) => writeln!(out, ": added {}", new.as_str())?,
+            (Some(old), None) => writeln!(out, ": removed {}", old.as_str())?,
+            (None, None) => writeln!(out)?,
+        }
+
+        for (path, change) in &package.changes {
+            match change {
+                FileChange::Added(node) => {
+                    let (typ, size) = node_summary(node);
+                    write!(out, "+ {} {:>12} ", typ, size.separated_string())?;
+                }
+                FileChange::Removed(node) => {
+                    let (typ, size) = node_summary(node);
+                    write!(out, "- {} {:>12} ", typ, size.separated_string())?;
+                }
+                FileChange::Changed { old, new } => {
+                    let (old_typ, old_size) = node_summary(old);
+                    let (new_typ, new_size) = node_summary(new);
+                    write!(
+                        out,
+                        "~ {} {:>12} -> {} {:>12} ",
+                        old_typ,
+                        old_size.separated_string(),
+                        new_typ,
+                        new_size.separated_string(),
+                    )?;
+                }
+            }
+            // paths are written as they are, they need not be valid UTF-8
+            out.write_all(path)?;
+            writeln!(out)?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// Writes the diff as JSON, with one object per changed file.
+fn write_diff_json<W: Write>(out: &mut W, packages: &[PackageChanges]) -> io::Result<()> {
+    for package in packages {
+        for (path, change) in &package.changes {
+            let (kind, old, new) = match change {
+                FileChange::Added(node) => ("added", None, Some(node)),
+                FileChange::Removed(node) => ("removed", Some(node), None),
+                FileChange::Changed { old, new } => ("changed", Some(old), Some(new)),
+            };
+            let node = |node: Option<&FileNode<()>>| {
+                node.map(|node| {
+                    let (typ, size) = node_summary(node);
+                    let target = match node {
+                        FileNode::Symlink { target } => Some(Bytes(target)),
+                        _ => None,
+                    };
+                    serde_json::json!({ "type": typ, "size": size, "target": target })
+                })
+            };
+            let value = serde_json::json!({
+                "attr": package.origin.attr,
+                "output": package.origin.output,
+                "system": package.origin.system,
+                "old_store_path": package.old.as_ref().map(|p| p.as_str()),
+                "new_store_path": package.new.as_ref().map(|p| p.as_str()),
+                "path": Bytes(path),
+                "change": kind,
+                "old": node(old),
+                "new": node(new),
+            });
+            serde_json::to_writer(&mut *out, &value)?;
+            writeln!(out)?;
+        }
+    }
+    Ok(())
+}
+
+/// Opens the existing database to reuse its file listings for `--incremental`.
+///
+/// Returns `None` if there is no database that can be reused.
+fn open_previous_database(args: &Args) -> Option<Reader> {
+    let reader = match Reader::open(args.database.join("files")) {
+        Ok(reader) => reader,
+        Err(e) => {
+            eprintln!("+ not reusing the existing database: {}", e);
+            return None;
+        }
+    };
+
+    match reader.metadata() {
+        Some(metadata) if args.filter_prefix.starts_with(&metadata.filter_prefix) => Some(reader),
+        Some(_) => {
+            eprintln!(
+                "+ not reusing the existing database: it was built with a different filter prefix"
+            );
+            None
+        }
+        None => {
+            eprintln!("+ not reusing the existing database: it was built by an older version of nix-index");
+            None
+        }
+    }
+}
+
 fn cache_dir() -> &'static OsStr {
     let base = xdg::BaseDirectories::with_prefix("nix-index");
     let cache_dir = Box::new(base.get_cache_home().unwrap());
@@ -133,8 +452,11 @@ fn cache_dir() -> &'static OsStr {
 
 /// Builds an index for nix-locate
 #[derive(Debug, Parser)]
-#[clap(author, about, version)]
+#[clap(author, about, version, args_conflicts_with_subcommands = true)]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     /// Make REQUESTS http requests in parallel
     #[clap(short = 'r', long = "requests", default_value = "100")]
     jobs: usize,
@@ -151,9 +473,8 @@ struct Args {
     #[clap(short = 's', long, value_name = "platform")]
     system: Option<String>,
 
-    /// Zstandard compression level
-    #[clap(short, long = "compression", default_value = "22")]
-    compression_level: i32,
+    #[clap(flatten)]
+    writer: WriterArgs,
 
     /// Show a stack trace in the case of a Nix evaluation error
     #[clap(long)]
@@ -170,6 +491,16 @@ struct Args {
     #[clap(long)]
     path_cache: bool,
 
+    /// Reuse the file listings of store paths that are already part of the existing database,
+    /// so that only the listings of new store paths need to be fetched.
+    #[clap(long)]
+    incremental: bool,
+
+    /// Directories whose files are added to an index for fast lookups of exact paths, such as
+    /// `nix-locate --whole-name --at-root /bin/foo`. Pass an empty value to disable the index.
+    #[clap(long, value_delimiter = ',', default_value = "/bin,/sbin,/lib")]
+    basename_index: Vec<String>,
+
     // We also add some additional sets that only show up in `nix-env -qa -A someSet`.
     //
     // Some of these sets are not build directly by hydra. We still include them here
@@ -183,11 +514,129 @@ struct Args {
     extra_scopes: Vec<String>,
 }
 
+/// Options for writing the database.
+#[derive(Debug, clap::Args)]
+struct WriterArgs {
+    /// Zstandard compression level
+    #[clap(short = 'c', long = "compression", default_value = "22")]
+    level: i32,
+
+    /// Train a Zstandard dictionary of at most BYTES on the first part of the index and use it
+    /// to compress the whole index. The dictionary is stored in the database. 0 disables the
+    /// dictionary.
+    #[clap(long, value_name = "BYTES", default_value = "0")]
+    dictionary_size: usize,
+
+    /// Enable Zstandard's long distance matching
+    #[clap(long)]
+    long: bool,
+
+    /// Order in which packages are written to the database
+    /// [default: hash with --reproducible, added otherwise]
+    #[clap(long, value_enum)]
+    order: Option<Order>,
+
+    /// Build a reproducible database, which is byte-identical for identical inputs. Packages
+    /// are fetched and written in a canonical order, and the creation time is taken from
+    /// SOURCE_DATE_EPOCH (or 0 if that is not set).
+    ///
+    /// Incremental builds only give identical results when they start from the same database.
+    #[clap(long)]
+    reproducible: bool,
+}
+
+/// The values of `--order`, see `PackageOrder`.
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum Order {
+    /// Packages are written in the order in which they are added.
+    Added,
+    /// Packages are sorted by the hash of their store path.
+    Hash,
+    /// Packages are sorted by their attribute path, output and system.
+    Attr,
+}
+
+impl From<Order> for PackageOrder {
+    fn from(order: Order) -> PackageOrder {
+        match order {
+            Order::Added => PackageOrder::Added,
+            Order::Hash => PackageOrder::Hash,
+            Order::Attr => PackageOrder::Attr,
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Merge several databases into a single one
+    Merge(MergeArgs),
+    /// Show the files that were added, removed or changed between two databases
+    Diff(DiffArgs),
+    /// Check the integrity of a database
+    Verify(VerifyArgs),
+    /// Export all packages and files of a database to SQLite
+    ExportSqlite(ExportSqliteArgs),
+}
+
+#[derive(Debug, clap::Args)]
+struct MergeArgs {
+    /// Directory where the merged index is stored
+    #[clap(short, long = "db", default_value_os = cache_dir(), env = "NIX_INDEX_DATABASE")]
+    database: PathBuf,
+
+    #[clap(flatten)]
+    writer: WriterArgs,
+
+    /// The databases to merge, either as a directory (like `--db`) or the path of the database
+    /// file itself. If a package is contained in multiple databases, the first one wins.
+    #[clap(required = true)]
+    inputs: Vec<PathBuf>,
+}
+
+#[derive(Debug, clap::Args)]
+struct DiffArgs {
+    /// The old database, either as a directory (like `--db`) or the path of the database file
+    old: PathBuf,
+
+    /// The new database, either as a directory (like `--db`) or the path of the database file
+    new: PathBuf,
+
+    /// Print one JSON object per changed file instead of the human readable output
+    #[clap(long)]
+    json: bool,
+}
+
+#[derive(Debug, clap::Args)]
+struct VerifyArgs {
+    /// The database to check, either as a directory or the path of the database file
+    #[clap(short, long = "db", default_value_os = cache_dir(), env = "NIX_INDEX_DATABASE")]
+    database: PathBuf,
+}
+
+#[derive(Debug, clap::Args)]
+struct ExportSqliteArgs {
+    /// The database to export, either as a directory or the path of the database file
+    #[clap(short, long = "db", default_value_os = cache_dir(), env = "NIX_INDEX_DATABASE")]
+    database: PathBuf,
+
+    /// Path for the resulting SQLite database. An existing file at this path is replaced.
+    #[clap(short, long, default_value = "files.sqlite")]
+    output: PathBuf,
+}
+
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
 
-    if let Err(e) = update_index(&args).await {
+    let result = match args.command {
+        Some(Command::Merge(ref merge)) => merge_databases(merge),
+        Some(Command::Diff(ref diff)) => diff_databases(diff),
+        Some(Command::Verify(ref verify)) => verify_database(verify),
+        Some(Command::ExportSqlite(ref export)) => export_sqlite(export),
+        None => update_index(&args).await,
+    };
+
+    if let Err(e) = result {
         eprintln!("error: {:?}", e);
         process::exit(2);
     }
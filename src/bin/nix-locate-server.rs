@@ -0,0 +1,370 @@
+//! HTTP server that answers searches in a nix-index database with JSON
+use std::convert::Infallible;
+use std::ffi::OsStr;
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use clap::Parser;
+use futures::{stream, StreamExt};
+use http_body_util::{BodyExt, Full, StreamBody};
+use hyper::body::{Bytes, Frame, Incoming};
+use hyper::header::{self, HeaderValue};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request as HttpRequest, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use nix_index::daemon::Database;
+use nix_index::files::FileType;
+use nix_index::format::{self, Format};
+use nix_index::locate::{Request, Search};
+use nix_index::util;
+use serde_json::json;
+use thiserror::Error;
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("reading from the database at '{database}' failed: {source}.\n\
+                     This may be caused by a corrupt or missing database, try (re)running `nix-index` to generate the database.")]
+    ReadDatabase {
+        database: PathBuf,
+        #[source]
+        source: nix_index::database::Error,
+    },
+    #[error("listening on '{address}' failed: {source}")]
+    Listen {
+        address: SocketAddr,
+        #[source]
+        source: io::Error,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+type Body = http_body_util::combinators::BoxBody<Bytes, io::Error>;
+
+/// The size of the chunks in which search results are sent to the client.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The main function of this module: answers requests until the server is killed.
+async fn serve(args: &Opts) -> Result<()> {
+    let index_file = args.database.join("files");
+    let database = tokio::task::spawn_blocking({
+        let index_file = index_file.clone();
+        move || Database::load(&index_file)
+    })
+    .await
+    .expect("loading the database panicked")
+    .map_err(|e| Error::ReadDatabase {
+        database: index_file,
+        source: e,
+    })?;
+    let database = Arc::new(Mutex::new(database));
+
+    let listener = TcpListener::bind(args.listen)
+        .await
+        .map_err(|e| Error::Listen {
+            address: args.listen,
+            source: e,
+        })?;
+    eprintln!("listening on http://{}", args.listen);
+
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(v) => v,
+            Err(e) => {
+                // Errors such as running out of file descriptors are temporary
+                eprintln!("warning: accepting a connection failed: {}", e);
+                continue;
+            }
+        };
+        let database = database.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |request| handle(request, database.clone()));
+            // Errors of a single connection only affect that client
+            let _ = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await;
+        });
+    }
+}
+
+/// Routes a request to the handler of its endpoint.
+async fn handle(
+    request: HttpRequest<Incoming>,
+    database: Arc<Mutex<Database>>,
+) -> std::result::Result<Response<Body>, Infallible> {
+    if request.method() != Method::GET {
+        return Ok(error(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "only GET is supported",
+        ));
+    }
+    let query = request.uri().query().unwrap_or("");
+    let response = match request.uri().path() {
+        "/search" => match parse_search(query) {
+            Ok(search) => search_response(search, database).await,
+            Err(message) => error(StatusCode::BAD_REQUEST, &message),
+        },
+        "/info" => info_response(database).await,
+        _ => error(StatusCode::NOT_FOUND, "no such endpoint"),
+    };
+    Ok(response)
+}
+
+/// Parses the query parameters of a search.
+///
+/// The parameters are named like the command line options of nix-locate, except that
+/// `toplevel=false` corresponds to `--all` and `group=false` to `--no-group`.
+fn parse_search(query: &str) -> std::result::Result<Search, String> {
+    let mut request = Request::default();
+    let mut pattern = None;
+    for (name, value) in form_urlencoded::parse(query.as_bytes()) {
+        let flag = || match &*value {
+            "" | "true" | "1" => Ok(true),
+            "false" | "0" => Ok(false),
+            _ => Err(format!("invalid value '{}' for '{}'", value, name)),
+        };
+        match &*name {
+            "pattern" => pattern = Some(value.into_owned()),
+            "regex" => request.regex = flag()?,
+            "whole_name" => request.whole_name = flag()?,
+            "at_root" => request.at_root = flag()?,
+            "package" => request.package = Some(value.into_owned()),
+            "attr" => request.attr = Some(value.into_owned()),
+            "output" => request.output = Some(value.into_owned()),
+            "hash" => request.hash = Some(value.into_owned()),
+            "system" => request.system = Some(value.into_owned()),
+            "all_systems" => request.all_systems = flag()?,
+            "min_size" => request.min_size = Some(util::parse_size(&value)?),
+            "max_size" => request.max_size = Some(util::parse_size(&value)?),
+            "target" => request.target = Some(value.into_owned()),
+            "type" => request.types.push(value.parse::<FileType>()?),
+            "toplevel" => request.all = !flag()?,
+            "group" => request.no_group = !flag()?,
+            _ => return Err(format!("unknown parameter '{}'", name)),
+        }
+    }
+    // searching only by symlink target does not need a pattern for the path
+    if request.target.is_some() {
+        pattern.get_or_insert_with(String::new);
+    }
+    request.pattern = pattern.ok_or("missing parameter 'pattern'")?;
+    Search::new(request).map_err(|e| e.to_string())
+}
+
+/// Runs a search and streams the matches to the client as a JSON array (in the format of
+/// `nix-locate --format json`).
+///
+/// Since the status has to be sent before the matches, errors that happen while the matches
+/// are being sent abort the response instead.
+async fn search_response(search: Search, database: Arc<Mutex<Database>>) -> Response<Body> {
+    let (started, start) = oneshot::channel();
+    let (chunks, mut receiver) = mpsc::channel(4);
+    tokio::task::spawn_blocking(move || {
+        let reader = database
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .reader();
+        let results = match reader.and_then(|reader| search.run(reader)) {
+            Ok(results) => results,
+            Err(e) => {
+                let _ = started.send(Err(e));
+                return;
+            }
+        };
+        if started.send(Ok(())).is_err() {
+            return;
+        }
+
+        let send = |chunk| chunks.blocking_send(chunk).is_ok();
+        let mut writer = format::Writer::new(Vec::new(), Format::Json);
+        for result in results {
+            let (store_path, entry) = match result {
+                Ok(v) => v,
+                Err(e) => {
+                    send(Err(io::Error::other(e)));
+                    return;
+                }
+            };
+            writer
+                .write(&store_path, &entry)
+                .expect("writing to a buffer cannot fail");
+            // Stop searching if the client went away
+            let buffer = writer.get_mut();
+            if buffer.len() >= CHUNK_SIZE && !send(Ok(mem::take(buffer).into())) {
+                return;
+            }
+        }
+        let buffer = writer.finish().expect("writing to a buffer cannot fail");
+        send(Ok(buffer.into()));
+    });
+
+    match start.await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => return error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+        Err(_) => return error(StatusCode::INTERNAL_SERVER_ERROR, "the search panicked"),
+    }
+    let chunks = stream::poll_fn(move |cx| receiver.poll_recv(cx));
+    let body = StreamBody::new(chunks.map(|chunk| chunk.map(Frame::data)));
+    json_response(StatusCode::OK, BodyExt::boxed(body))
+}
+
+/// Returns the format version and metadata of the database.
+async fn info_response(database: Arc<Mutex<Database>>) -> Response<Body> {
+    let reader = tokio::task::spawn_blocking(move || {
+        database
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .reader()
+    })
+    .await;
+    match reader {
+        Ok(Ok(reader)) => {
+            let info = json!({
+                "format_version": reader.format_version(),
+                "metadata": reader.metadata(),
+            });
+            json_response(StatusCode::OK, full(info.to_string()))
+        }
+        Ok(Err(e)) => error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+        Err(_) => error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "loading the database panicked",
+        ),
+    }
+}
+
+/// Returns a response with a JSON object describing an error.
+fn error(status: StatusCode, message: &str) -> Response<Body> {
+    json_response(status, full(json!({ "error": message }).to_string()))
+}
+
+fn full(body: String) -> Body {
+    BodyExt::boxed(Full::new(Bytes::from(body)).map_err(|e| match e {}))
+}
+
+fn json_response(status: StatusCode, body: Body) -> Response<Body> {
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    response
+}
+
+fn cache_dir() -> &'static OsStr {
+    let base = xdg::BaseDirectories::with_prefix("nix-index");
+    let cache_dir = Box::new(base.get_cache_home().unwrap());
+    let cache_dir = Box::leak(cache_dir);
+    cache_dir.as_os_str()
+}
+
+const LONG_USAGE: &str = r#"
+Endpoints
+=========
+
+GET /search?pattern=PATTERN
+    Searches for files, like `nix-locate PATTERN`. The response is a JSON array with an object
+    for each match, which is streamed while the search is running. The other parameters mirror
+    the options of nix-locate:
+
+    regex=true, whole_name=true, at_root=true, package=NAME, attr=ATTR, output=NAME, hash=HASH,
+    system=SYSTEM, all_systems=true, min_size=SIZE, max_size=SIZE (such as 10M),
+    target=PATTERN, type=r|x|d|s (may be given multiple times), toplevel=false (like --all),
+    group=false (like --no-group)
+
+GET /info
+    Returns the format version and metadata of the database, like `nix-locate --info`.
+
+Example:
+
+$ curl 'http://127.0.0.1:8080/search?pattern=bin/hello&whole_name=true&type=x'
+[
+{"attr":"hello","output":"out","toplevel":true,"system":"x86_64-linux","store_path":"/nix/store/...-hello-2.12.1","path":"/bin/hello","type":"x","size":52344,"target":null}
+]
+
+The database is loaded into memory at startup and reloaded when it is replaced.
+"#;
+
+/// Answers searches in a nix-index database over HTTP
+#[derive(Debug, Parser)]
+#[clap(author, about, version, after_help = LONG_USAGE)]
+struct Opts {
+    /// Directory where the index is stored
+    #[clap(short, long = "db", default_value_os = cache_dir(), env = "NIX_INDEX_DATABASE")]
+    database: PathBuf,
+
+    /// Address on which to listen for HTTP requests
+    #[clap(short, long, default_value = "127.0.0.1:8080")]
+    listen: SocketAddr,
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Opts::parse();
+
+    if let Err(e) = serve(&args).await {
+        eprintln!("error: {}", e);
+        process::exit(2);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(query: &str) -> std::result::Result<Request, String> {
+        parse_search(query).map(|search| search.request().clone())
+    }
+
+    #[test]
+    fn test_parse_search() {
+        assert_eq!(
+            parse("pattern=bin%2Fhello&whole_name&toplevel=false&min_size=1K"),
+            Ok(Request {
+                pattern: "bin/hello".to_string(),
+                whole_name: true,
+                all: true,
+                min_size: Some(1024),
+                ..Request::default()
+            })
+        );
+        assert_eq!(
+            parse("pattern=x&type=x&type=s").map(|r| r.types),
+            Ok(vec![
+                FileType::Regular { executable: true },
+                FileType::Symlink
+            ])
+        );
+        // searching by symlink target does not need a pattern
+        assert_eq!(
+            parse("target=libGL").map(|r| (r.pattern, r.target)),
+            Ok((String::new(), Some("libGL".to_string())))
+        );
+    }
+
+    #[test]
+    fn test_parse_search_errors() {
+        assert_eq!(
+            parse("pattern=x&color=red"),
+            Err("unknown parameter 'color'".to_string())
+        );
+        assert_eq!(
+            parse("pattern=x&regex=yes"),
+            Err("invalid value 'yes' for 'regex'".to_string())
+        );
+        assert_eq!(
+            parse("whole_name=true"),
+            Err("missing parameter 'pattern'".to_string())
+        );
+        assert!(parse("pattern=x&type=q").is_err());
+        assert!(parse("pattern=(&regex").is_err());
+    }
+}
@@ -1,17 +1,25 @@
 //! Tool for searching for files in nixpkgs packages
+use std::cell::Cell;
 use std::collections::HashSet;
 use std::ffi::OsStr;
-use std::path::PathBuf;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 use std::process;
 use std::result;
 use std::str;
 use std::str::FromStr;
 
+use clap::error::ErrorKind;
 use clap::{value_parser, Parser};
-use nix_index::database;
-use nix_index::files::{self, FileTreeEntry, FileType};
+use indexmap::IndexMap;
+use nix_index::files::{self, FileNode, FileTree, FileTreeEntry, FileType};
+use nix_index::format::{self, Format, Template};
+use nix_index::locate::{self, Owner, Request, Search, SortKey};
+use nix_index::package::StorePath;
+use nix_index::{cache, daemon, database, util};
 use owo_colors::{OwoColorize, Stream};
-use regex::bytes::Regex;
 use separator::Separatable;
 use thiserror::Error;
 
@@ -37,6 +45,22 @@ pub enum Error {
         #[source]
         source: database::Error,
     },
+    #[error("searching with the daemon at '{socket}' failed: {source}. Use --no-daemon to search the database directly.")]
+    Daemon {
+        socket: PathBuf,
+        #[source]
+        source: daemon::Error,
+    },
+    #[error("writing the results failed: {source}")]
+    WriteOutput {
+        #[from]
+        source: io::Error,
+    },
+    #[error("starting the daemon failed: {source}")]
+    StartDaemon {
+        #[source]
+        source: io::Error,
+    },
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -45,178 +69,676 @@ pub type Result<T> = std::result::Result<T, Error>;
 struct Args {
     /// Path of the nix-index database.
     database: PathBuf,
-    /// The pattern to search for. This is always in regex syntax.
-    pattern: String,
-    group: bool,
-    hash: Option<String>,
-    package_pattern: Option<String>,
-    file_type: Vec<FileType>,
-    only_toplevel: bool,
+    /// The search to run.
+    request: Request,
     color: bool,
     minimal: bool,
+    long: bool,
+    format: Option<Format>,
+    template: Option<Template>,
+    sort: Option<SortKey>,
+    reverse: bool,
+    limit: Option<usize>,
+    count: bool,
+    count_per_package: bool,
+    size_per_package: bool,
+    quiet: bool,
+    /// The attribute of the packages to list with `--list`, which is empty if only `--hash`
+    /// selects the packages.
+    list: Option<String>,
+    tree: bool,
+    owner: Option<PathBuf>,
+    info: bool,
+    cache: bool,
+    daemon: bool,
+    no_daemon: bool,
 }
 
 /// The main function of this module: searches with the given options in the database.
-fn locate(args: &Args) -> Result<()> {
-    // Build the regular expression matcher
-    let pattern = Regex::new(&args.pattern).map_err(|e| Error::Grep {
-        pattern: args.pattern.clone(),
-        source: e,
+/// Returns whether anything matched.
+///
+/// If a daemon is running for the database, the search is done by the daemon.
+fn locate(args: &Args) -> Result<bool> {
+    let search = Search::new(args.request.clone()).map_err(|e| Error::Grep {
+        pattern: e.pattern,
+        source: e.source,
     })?;
-    let package_pattern = if let Some(ref pat) = args.package_pattern {
-        Some(Regex::new(pat).map_err(|e| Error::Grep {
-            pattern: pat.clone(),
+    let index_file = args.database.join("files");
+
+    // If there is no runtime directory, no daemon can be running.
+    if let (false, Ok(socket)) = (args.no_daemon, daemon::socket_path(&index_file)) {
+        let daemon_error = |e| Error::Daemon {
+            socket: socket.clone(),
             source: e,
-        })?)
-    } else {
-        None
-    };
+        };
+        if let Some(results) = daemon::query(&socket, search.request()).map_err(daemon_error)? {
+            return print_results(args, &search, results.map(|r| r.map_err(daemon_error)));
+        }
+    }
 
     // Open the database
-    let index_file = args.database.join("files");
-    let db = database::Reader::open(&index_file).map_err(|e| Error::ReadDatabase {
+    let mut db = database::Reader::open(&index_file).map_err(|e| Error::ReadDatabase {
         database: index_file.clone(),
         source: e,
     })?;
 
-    let results = db
-        .query(&pattern)
-        .package_pattern(package_pattern.as_ref())
-        .hash(args.hash.clone())
-        .run()
-        .map_err(|e| Error::SearchDatabase {
-            database: index_file.clone(),
-            source: e,
-        })?
-        .filter(|v| {
-            v.as_ref().ok().map_or_else(
-                || true,
-                |v| {
-                    let &(ref store_path, FileTreeEntry { ref path, ref node }) = v;
-                    let m = pattern
-                        .find_iter(path)
-                        .last()
-                        .expect("path should match the pattern");
-
-                    let conditions = [
-                        !args.group || !path[m.end()..].contains(&b'/'),
-                        !args.only_toplevel || store_path.origin().toplevel,
-                        args.file_type.iter().any(|t| &node.get_type() == t),
-                    ];
-
-                    conditions.iter().all(|c| *c)
-                },
-            )
-        });
+    // The cache only makes searching faster, so we can still search without it
+    if args.cache {
+        let result = cache::default_path(&index_file)
+            .map_err(database::Error::from)
+            .and_then(|path| db.use_cache(path));
+        if let Err(e) = result {
+            eprintln!("warning: searching without the cache: {}", e);
+        }
+    }
 
-    let mut printed_attrs = HashSet::new();
+    let results = search.run(db).map_err(|e| Error::SearchDatabase {
+        database: index_file.clone(),
+        source: e,
+    })?;
+    print_results(
+        args,
+        &search,
+        results.map(|r| {
+            r.map_err(|e| Error::ReadDatabase {
+                database: index_file.clone(),
+                source: e,
+            })
+        }),
+    )
+}
+
+/// Prints the results of a search. Returns whether there were any results.
+fn print_results(
+    args: &Args,
+    search: &Search,
+    results: impl Iterator<Item = Result<(StorePath, FileTreeEntry)>>,
+) -> Result<bool> {
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    write_results(&mut out, args, search, results)
+}
+
+/// Writes the results of a search as selected by the arguments. Returns whether there were any
+/// results.
+///
+/// Results are only read from the database as far as they are needed, so the search stops
+/// early if only the first few results are printed.
+fn write_results<W: Write>(
+    out: &mut W,
+    args: &Args,
+    search: &Search,
+    results: impl Iterator<Item = Result<(StorePath, FileTreeEntry)>>,
+) -> Result<bool> {
+    let limit = args.limit.unwrap_or(usize::MAX);
+    if args.quiet {
+        return Ok(results.take(limit).next().transpose()?.is_some());
+    }
+
+    // sorting needs all results, while otherwise they are printed as soon as they are found
+    let results: Box<dyn Iterator<Item = Result<(StorePath, FileTreeEntry)>>> = match args.sort {
+        Some(key) => {
+            let mut results = results.collect::<Result<Vec<_>>>()?;
+            key.sort(&mut results, args.reverse);
+            Box::new(results.into_iter().map(Ok))
+        }
+        None => Box::new(results),
+    };
+    let matched = Cell::new(false);
+    let results = results.take(limit).inspect(|r| {
+        if r.is_ok() {
+            matched.set(true);
+        }
+    });
+
+    let result = if args.count {
+        write_count(out, results)
+    } else if args.count_per_package || args.size_per_package {
+        write_per_package(out, args, results)
+    } else if let Some(format) = args.format {
+        write_formatted(format::Writer::new(&mut *out, format), results)
+    } else if let Some(template) = &args.template {
+        write_formatted(
+            format::Writer::with_template(&mut *out, template.clone()),
+            results,
+        )
+    } else {
+        write_text(out, args, search, results)
+    };
+    finish_output(out, result)?;
+    Ok(matched.get())
+}
+
+/// Writes the number of results.
+fn write_count<W: Write>(
+    out: &mut W,
+    results: impl Iterator<Item = Result<(StorePath, FileTreeEntry)>>,
+) -> Result<()> {
+    let mut count = 0u64;
     for v in results {
-        let (store_path, FileTreeEntry { path, node }) = v.map_err(|e| Error::ReadDatabase {
-            database: index_file.clone(),
-            source: e,
-        })?;
+        v?;
+        count += 1;
+    }
+    writeln!(out, "{}", count)?;
+    Ok(())
+}
 
-        use crate::files::FileNode::*;
-        let (typ, size) = match node {
-            Regular { executable, size } => (if executable { "x" } else { "r" }, size),
-            Directory { size, contents: () } => ("d", size),
-            Symlink { .. } => ("s", 0),
+/// Writes the number of results of each package, in the order in which the packages were
+/// first found. With `--size-per-package`, the total size of the results of each package is
+/// written instead, with the largest packages first.
+fn write_per_package<W: Write>(
+    out: &mut W,
+    args: &Args,
+    results: impl Iterator<Item = Result<(StorePath, FileTreeEntry)>>,
+) -> Result<()> {
+    let mut totals = IndexMap::<StorePath, u64>::new();
+    for v in results {
+        let (store_path, entry) = v?;
+        *totals.entry(store_path).or_default() += match entry.node {
+            // directory sizes are the number of entries, which cannot be summed up with bytes
+            FileNode::Regular { size, .. } if args.size_per_package => size,
+            _ if args.size_per_package => 0,
+            _ => 1,
         };
+    }
+    if args.size_per_package {
+        // stable, so packages with the same size stay in the order in which they were found
+        totals.sort_by(|_, a, _, b| b.cmp(a));
+    }
+    for (store_path, total) in totals {
+        writeln!(
+            out,
+            "{:<40} {:>14} {}",
+            attr_label(&store_path, args.request.all_systems),
+            total.separated_string(),
+            store_path.as_str()
+        )?;
+    }
+    Ok(())
+}
 
-        let mut attr = format!(
-            "{}.{}",
-            store_path.origin().attr,
-            store_path.origin().output
-        );
+/// Returns the attribute and output of a package as shown in the results. Packages that are
+/// not top-level packages are shown in parentheses.
+///
+/// If `show_system` is set, the system of the package is shown as well. This is done with
+/// `--all-systems`, since the results may then contain packages for different systems.
+fn attr_label(store_path: &StorePath, show_system: bool) -> String {
+    let origin = store_path.origin();
+    let mut attr = format!("{}.{}", origin.attr, origin.output);
+    if !origin.toplevel {
+        attr = format!("({})", attr);
+    }
+    if let (true, Some(system)) = (show_system, &origin.system) {
+        attr = format!("{} ({})", attr, system);
+    }
+    attr
+}
 
-        if !store_path.origin().toplevel {
-            attr = format!("({})", attr);
-        }
+/// Writes the results in one of the machine-readable formats.
+fn write_formatted<W: Write>(
+    mut writer: format::Writer<W>,
+    results: impl Iterator<Item = Result<(StorePath, FileTreeEntry)>>,
+) -> Result<()> {
+    for v in results {
+        let (store_path, entry) = v?;
+        writer.write(&store_path, &entry)?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/// Writes the results as a table for humans, or only the attributes with `--minimal`.
+fn write_text<W: Write>(
+    out: &mut W,
+    args: &Args,
+    search: &Search,
+    results: impl Iterator<Item = Result<(StorePath, FileTreeEntry)>>,
+) -> Result<()> {
+    let mut printed_attrs = HashSet::new();
+    for v in results {
+        let (store_path, FileTreeEntry { path, node }) = v?;
+        let (typ, size, target) = columns(node, args.long);
+
+        let attr = attr_label(&store_path, args.request.all_systems && !args.minimal);
 
         if args.minimal {
             // only print each package once, even if there are multiple matches
             if printed_attrs.insert(attr.clone()) {
-                println!("{}", attr);
+                writeln!(out, "{}", attr)?;
             }
         } else {
-            print!(
+            write!(
+                out,
                 "{:<40} {:>14} {:>1} {}",
                 attr,
-                size.separated_string(),
+                size,
                 typ,
                 store_path.as_str()
-            );
+            )?;
 
             let path = String::from_utf8_lossy(&path);
 
             if args.color {
                 let mut prev = 0;
-                for mat in pattern.find_iter(path.as_bytes()) {
+                for mat in search.pattern().find_iter(path.as_bytes()) {
                     // if the match is empty, we need to make sure we don't use string
                     // indexing because the match may be "inside" a single multibyte character
                     // in that case (for example, the pattern may match the second byte of a multibyte character)
                     if mat.start() == mat.end() {
                         continue;
                     }
-                    print!(
+                    write!(
+                        out,
                         "{}{}",
                         &path[prev..mat.start()],
                         (&path[mat.start()..mat.end()])
                             .if_supports_color(Stream::Stdout, |txt| txt.red()),
-                    );
+                    )?;
                     prev = mat.end();
                 }
-                println!("{}", &path[prev..]);
+                write!(out, "{}", &path[prev..])?;
             } else {
-                println!("{}", path);
+                write!(out, "{}", path)?;
             }
+            write_line_end(out, target)?;
         }
     }
 
     Ok(())
 }
 
-/// Extract the parsed arguments for clap's arg matches.
+/// Returns the type and size of a file as shown in the output, and with `--long` the target
+/// of symlinks.
+fn columns(node: FileNode<()>, long: bool) -> (&'static str, String, Option<Vec<u8>>) {
+    use crate::files::FileNode::*;
+    match node {
+        Regular { executable, size } => (
+            if executable { "x" } else { "r" },
+            size.separated_string(),
+            None,
+        ),
+        Directory { size, contents: () } => ("d", size.separated_string(), None),
+        // symlinks have no size, which is made clear in the long format
+        Symlink { target } if long => ("s", "-".to_string(), Some(target.into_vec())),
+        Symlink { .. } => ("s", "0".to_string(), None),
+    }
+}
+
+/// Ends the line of a file, after writing the target if the file is a symlink.
+fn write_line_end<W: Write>(out: &mut W, target: Option<Vec<u8>>) -> io::Result<()> {
+    if let Some(target) = target {
+        write!(out, " -> {}", String::from_utf8_lossy(&target))?;
+    }
+    writeln!(out)
+}
+
+/// Lists all files of the packages selected with `--list` and `--hash`. Returns whether any
+/// package was found.
 ///
-/// Handles parsing the values of more complex arguments.
-fn process_args(matches: Opts) -> result::Result<Args, clap::Error> {
-    let pattern_arg = matches.pattern;
-    let package_arg = matches.package;
+/// `attr` is either an attribute, which selects all outputs of the packages with that
+/// attribute, or an attribute and an output (like `openssl.dev`). If it is empty, only the
+/// hash selects the packages.
+fn list(args: &Args, attr: &str) -> Result<bool> {
+    let index_file = args.database.join("files");
+    let read_error = |e| Error::ReadDatabase {
+        database: index_file.clone(),
+        source: e,
+    };
+    let db = database::Reader::open(&index_file).map_err(read_error)?;
 
-    let start_anchor = if matches.at_root { "^" } else { "" };
-    let end_anchor = if matches.whole_name { "$" } else { "" };
+    let request = &args.request;
+    let system = request.system_for(&db);
+    let packages = db
+        .package_entries(|store_path| {
+            let origin = store_path.origin();
+            let conditions = [
+                attr.is_empty()
+                    || origin.attr == attr
+                    || format!("{}.{}", origin.attr, origin.output) == attr,
+                request
+                    .hash
+                    .as_ref()
+                    .is_none_or(|h| *h == store_path.hash()),
+                // a hash selects a single store path, which may not be a top-level package
+                request.all || request.hash.is_some() || origin.toplevel,
+                system
+                    .as_ref()
+                    .is_none_or(|s| origin.system.as_ref().is_none_or(|ps| ps == s)),
+            ];
+            conditions.iter().all(|c| *c)
+        })
+        .map_err(read_error)?;
+    let found = !packages.is_empty();
 
-    let make_pattern = |s: &str, wrap: bool| {
-        let regex = if matches.regex {
-            s.to_string()
-        } else {
-            regex::escape(s)
-        };
-        if wrap {
-            format!("{}{}{}", start_anchor, regex, end_anchor)
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    let entries = packages.into_iter().map(|(store_path, mut entries)| {
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        // the root of the package is the store path itself, which is not listed as a file
+        entries.retain(|entry| !entry.path.is_empty());
+        (store_path, entries)
+    });
+    let result = if let Some(format) = args.format {
+        write_formatted(
+            format::Writer::new(&mut out, format),
+            entries.flat_map(|(store_path, entries)| {
+                entries
+                    .into_iter()
+                    .map(move |entry| Ok((store_path.clone(), entry)))
+            }),
+        )
+    } else if let Some(template) = &args.template {
+        write_formatted(
+            format::Writer::with_template(&mut out, template.clone()),
+            entries.flat_map(|(store_path, entries)| {
+                entries
+                    .into_iter()
+                    .map(move |entry| Ok((store_path.clone(), entry)))
+            }),
+        )
+    } else {
+        write_listing(&mut out, args, entries)
+    };
+    finish_output(&mut out, result)?;
+    Ok(found)
+}
+
+/// Writes the files of each package for `--list`, as a flat list or as a tree with `--tree`.
+fn write_listing<W: Write>(
+    out: &mut W,
+    args: &Args,
+    packages: impl Iterator<Item = (StorePath, Vec<FileTreeEntry>)>,
+) -> Result<()> {
+    for (i, (store_path, entries)) in packages.enumerate() {
+        if i > 0 {
+            writeln!(out)?;
+        }
+        writeln!(
+            out,
+            "{:<40} {}",
+            attr_label(&store_path, args.request.all_systems),
+            store_path.as_str()
+        )?;
+        if args.tree {
+            write_tree(out, args, &FileTree::from_list(entries), &mut String::new())?;
+            continue;
+        }
+        for FileTreeEntry { path, node } in entries {
+            let (typ, size, target) = columns(node, args.long);
+            write!(
+                out,
+                "{:>14} {:>1} {}",
+                size,
+                typ,
+                String::from_utf8_lossy(&path)
+            )?;
+            write_line_end(out, target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes the contents of a directory as a tree, where `prefix` holds the lines that connect
+/// the parent directories.
+fn write_tree<W: Write>(
+    out: &mut W,
+    args: &Args,
+    tree: &FileTree,
+    prefix: &mut String,
+) -> Result<()> {
+    let children = tree.children();
+    let count = children.len();
+    for (i, (name, child)) in children.into_iter().enumerate() {
+        let last = i + 1 == count;
+        let (typ, size, target) = columns(child.node(), args.long);
+        write!(
+            out,
+            "{:>14} {:>1} {}{}{}",
+            size,
+            typ,
+            prefix,
+            if last { "└── " } else { "├── " },
+            String::from_utf8_lossy(name)
+        )?;
+        write_line_end(out, target)?;
+
+        let len = prefix.len();
+        prefix.push_str(if last { "    " } else { "│   " });
+        write_tree(out, args, child, prefix)?;
+        prefix.truncate(len);
+    }
+    Ok(())
+}
+
+/// Prints the package that provides the file at `path`, like `dpkg -S`. Returns whether the
+/// package was found.
+fn owner(args: &Args, path: &Path) -> Result<bool> {
+    let index_file = args.database.join("files");
+    let read_error = |e| Error::ReadDatabase {
+        database: index_file.clone(),
+        source: e,
+    };
+    let db = database::Reader::open(&index_file).map_err(read_error)?;
+
+    let mut owner = locate::find_owner(&db, path.as_os_str().as_bytes()).map_err(read_error)?;
+    // paths outside the store (like `/run/current-system/sw/bin/hello`) are symlinks into it
+    if owner.is_none() {
+        if let Ok(resolved) = fs::canonicalize(path) {
+            owner = locate::find_owner(&db, resolved.as_os_str().as_bytes()).map_err(read_error)?;
+        }
+    }
+    let Some(Owner {
+        store_path,
+        path: file,
+        node,
+    }) = owner
+    else {
+        eprintln!("no package in the database provides '{}'", path.display());
+        return Ok(false);
+    };
+
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    let result = if args.minimal {
+        writeln!(out, "{}", attr_label(&store_path, false)).map_err(Error::from)
+    } else {
+        write_owner(&mut out, &store_path, &file, node)
+    };
+    finish_output(&mut out, result)?;
+    Ok(true)
+}
+
+/// Writes the origin of the package that provides a file, and whether the file is indexed.
+fn write_owner<W: Write>(
+    out: &mut W,
+    store_path: &StorePath,
+    file: &[u8],
+    node: Option<FileNode<()>>,
+) -> Result<()> {
+    let origin = store_path.origin();
+    let indexed = match node {
+        Some(node) => {
+            let (typ, size, target) = columns(node, true);
+            let mut indexed = format!("yes (type {}, size {})", typ, size);
+            if let Some(target) = target {
+                indexed = format!("{} -> {}", indexed, String::from_utf8_lossy(&target));
+            }
+            indexed
+        }
+        None => "no (the file listing of the package does not contain this path)".to_string(),
+    };
+    let fields = [
+        (
+            "path:",
+            format!("{}{}", store_path.as_str(), String::from_utf8_lossy(file)),
+        ),
+        ("store path:", store_path.as_str().into_owned()),
+        ("attr:", origin.attr.clone()),
+        ("output:", origin.output.clone()),
+        (
+            "toplevel:",
+            if origin.toplevel {
+                "yes".to_string()
+            } else {
+                format!("no (a dependency of {})", origin.attr)
+            },
+        ),
+        (
+            "system:",
+            origin
+                .system
+                .clone()
+                .unwrap_or_else(|| "unknown".to_string()),
+        ),
+        ("indexed:", indexed),
+    ];
+    for (name, value) in fields {
+        writeln!(out, "{:<12} {}", name, value)?;
+    }
+    Ok(())
+}
+
+/// Flushes the output after the results have been written.
+fn finish_output<W: Write>(out: &mut W, result: Result<()>) -> Result<()> {
+    match result.and_then(|()| out.flush().map_err(Error::from)) {
+        // the reader of the output went away (e.g. `| head`), which is not an error
+        Err(Error::WriteOutput { source }) if source.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => result,
+    }
+}
+
+/// Runs a daemon that answers the searches of other nix-locate processes.
+fn run_daemon(args: &Args) -> Result<()> {
+    let index_file = args.database.join("files");
+    let socket = daemon::socket_path(&index_file)
+        .and_then(|socket| daemon::bind(&socket).map(|listener| (socket, listener)));
+    let (socket, listener) = socket.map_err(|e| Error::StartDaemon { source: e })?;
+    eprintln!("listening on {}", socket.display());
+    daemon::serve(listener, &index_file).map_err(|e| Error::ReadDatabase {
+        database: index_file,
+        source: e,
+    })
+}
+
+/// Prints the metadata of the database.
+fn print_info(args: &Args) -> Result<()> {
+    let index_file = args.database.join("files");
+    let db = database::Reader::open(&index_file).map_err(|e| Error::ReadDatabase {
+        database: index_file.clone(),
+        source: e,
+    })?;
+
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    let result = write_info(&mut out, &index_file, &db);
+    finish_output(&mut out, result)
+}
+
+/// Writes the metadata of the database at `index_file`.
+fn write_info<W: Write>(out: &mut W, index_file: &Path, db: &database::Reader) -> Result<()> {
+    writeln!(out, "{:<16} {}", "database:", index_file.display())?;
+    writeln!(out, "{:<16} {}", "format version:", db.format_version())?;
+
+    let Some(metadata) = db.metadata() else {
+        writeln!(
+            out,
+            "no metadata stored (the database was built by an older version of nix-index)"
+        )?;
+        return Ok(());
+    };
+
+    let or_unknown = |v: Option<&str>| v.unwrap_or("unknown").to_string();
+    let list = |v: &[String]| {
+        if v.is_empty() {
+            "none".to_string()
         } else {
-            regex
+            v.join(", ")
         }
     };
+    let fields = [
+        ("generator:", metadata.generator.clone()),
+        ("created:", util::format_timestamp(metadata.created)),
+        ("nixpkgs:", or_unknown(metadata.nixpkgs.as_deref())),
+        (
+            "nixpkgs version:",
+            or_unknown(metadata.nixpkgs_version.as_deref()),
+        ),
+        ("systems:", list(&metadata.systems)),
+        ("extra scopes:", list(&metadata.extra_scopes)),
+        (
+            "filter prefix:",
+            if metadata.filter_prefix.is_empty() {
+                "none".to_string()
+            } else {
+                metadata.filter_prefix.clone()
+            },
+        ),
+        ("packages:", metadata.packages.separated_string()),
+        ("entries:", metadata.entries.separated_string()),
+    ];
+    for (name, value) in fields {
+        writeln!(out, "{:<16} {}", name, value)?;
+    }
+
+    Ok(())
+}
 
+/// Extract the parsed arguments for clap's arg matches.
+///
+/// Handles parsing the values of more complex arguments.
+fn process_args(matches: Opts) -> result::Result<Args, clap::Error> {
     let color = match matches.color {
         Color::Auto => atty::is(atty::Stream::Stdout),
         Color::Always => true,
         Color::Never => false,
     };
 
+    if let (Some(None), None) = (&matches.list, &matches.hash) {
+        return Err(clap::Error::raw(
+            ErrorKind::MissingRequiredArgument,
+            "--list needs either ATTR[.OUTPUT] or --hash\n",
+        ));
+    }
+
     let args = Args {
         database: matches.database,
-        group: !matches.no_group,
-        pattern: make_pattern(&pattern_arg, true),
-        package_pattern: package_arg.as_deref().map(|p| make_pattern(p, false)),
-        hash: matches.hash,
-        file_type: matches
-            .r#type
-            .unwrap_or_else(|| files::ALL_FILE_TYPES.to_vec()),
-        only_toplevel: !matches.all,
+        request: Request {
+            pattern: matches.pattern.unwrap_or_default(),
+            regex: matches.regex,
+            whole_name: matches.whole_name,
+            at_root: matches.at_root,
+            package: matches.package,
+            attr: matches.attr,
+            output: matches.output,
+            hash: matches.hash,
+            system: matches.system,
+            all_systems: matches.all_systems,
+            min_size: matches.min_size,
+            max_size: matches.max_size,
+            target: matches.target,
+            types: matches.r#type.unwrap_or_default(),
+            all: matches.all,
+            no_group: matches.no_group,
+        },
         color,
         minimal: matches.minimal,
+        long: matches.long,
+        format: matches.format,
+        template: matches.template,
+        sort: matches.sort,
+        reverse: matches.reverse,
+        limit: matches.limit,
+        count: matches.count,
+        count_per_package: matches.count_per_package,
+        size_per_package: matches.size_per_package,
+        quiet: matches.quiet,
+        list: matches.list.map(Option::unwrap_or_default),
+        tree: matches.tree,
+        owner: matches.owner,
+        info: matches.info,
+        cache: matches.cache,
+        daemon: matches.daemon,
+        no_daemon: matches.no_daemon,
     };
     Ok(args)
 }
@@ -249,6 +771,12 @@ Limitations
 
   This means that we don't know what nixpkgs attribute produces /nix/store/nl581g5kv3m2xnmmfgb678n91d7ll4vv-ghc-8.0.2-with-packages,
   but we know that `xmonad-with-packages.out` requires it.
+
+Exit status
+===========
+
+Like grep, nix-locate exits with status 0 if there are any results, 1 if there are none and 2 if
+an error occurred. Use `nix-locate --quiet` to only check whether there are any results.
 "#;
 
 fn cache_dir() -> &'static OsStr {
@@ -264,13 +792,15 @@ fn cache_dir() -> &'static OsStr {
 struct Opts {
     /// Pattern for which to search
     // #[clap(name = "PATTERN")]
-    pattern: String,
+    #[clap(required_unless_present_any = ["info", "daemon", "target", "list", "owner"])]
+    pattern: Option<String>,
 
     /// Directory where the index is stored
     #[clap(short, long = "db", default_value_os = cache_dir(), env = "NIX_INDEX_DATABASE")]
     database: PathBuf,
 
-    /// Treat PATTERN as regex instead of literal text. Also applies to NAME.
+    /// Treat PATTERN as regex instead of literal text. Also applies to PACKAGE, ATTR, --output
+    /// and --target.
     #[clap(short, long)]
     regex: bool,
 
@@ -278,16 +808,56 @@ struct Opts {
     #[clap(short, long)]
     package: Option<String>,
 
+    /// Only print matches from packages whose nixpkgs attribute matches ATTR, such as
+    /// `python3Packages.` or, with --regex, `^python3Packages\.`. Like PATTERN, ATTR matches
+    /// any part of the attribute, unless --whole-name is given. For packages that are not
+    /// top-level packages, this is the attribute of a package that depends on them.
+    #[clap(long)]
+    attr: Option<String>,
+
+    /// Only print matches from outputs whose name matches NAME, such as `dev` or `bin`. Like
+    /// PATTERN, NAME matches any part of the output name, unless --whole-name is given.
+    #[clap(long, value_name = "NAME")]
+    output: Option<String>,
+
     /// Only print matches from the package that has the given HASH.
     #[clap(long, name = "HASH")]
     hash: Option<String>,
 
+    /// Only print matches from packages for the given system, such as `aarch64-linux`. If the
+    /// database contains packages for more than one system, only packages for the current
+    /// system are printed by default.
+    #[clap(long)]
+    system: Option<String>,
+
+    /// Print matches from packages for all systems, and show the system of each package.
+    #[clap(long, conflicts_with = "system")]
+    all_systems: bool,
+
+    /// Only print regular files that are at least SIZE large. The size is given in bytes, or
+    /// with a unit such as 10K, 1.5M or 2G (powers of 1024) or 10kB, 1.5MB or 2GB (powers
+    /// of 1000). Directories and symlinks are not printed if this option is given.
+    #[clap(long, value_name = "SIZE", value_parser = util::parse_size)]
+    min_size: Option<u64>,
+
+    /// Only print regular files that are at most SIZE large, with the same units as
+    /// --min-size. Directories and symlinks are not printed if this option is given.
+    #[clap(long, value_name = "SIZE", value_parser = util::parse_size)]
+    max_size: Option<u64>,
+
+    /// Only print symlinks whose target matches TARGET, such as `/run/opengl-driver` or a store
+    /// path. Like PATTERN, TARGET is treated as a regex with --regex. If no PATTERN is given,
+    /// all symlinks with a matching target are printed.
+    #[clap(long)]
+    target: Option<String>,
+
     /// Print all matches, not only print from packages that show up in `nix-env -qa`.
     #[clap(long)]
     all: bool,
 
     /// Only print matches for files that have this type. If the option is given multiple times,
-    /// a file will be printed if it has any of the given types.
+    /// a file will be printed if it has any of the given types. Symlinks that resolve to an
+    /// executable, even in another package, are also printed for `--type x`.
     /// [options: (r)egular file, e(x)cutable, (d)irectory, (s)ymlink]
     #[clap(short, long, value_parser=value_parser!(FileType))]
     r#type: Option<Vec<FileType>>,
@@ -306,7 +876,7 @@ struct Opts {
 
     /// Only print matches for files or directories whose basename matches PATTERN exactly.
     /// This means that the pattern `bin/foo` will only match a file called `bin/foo` or
-    /// `xx/bin/foo` but not `bin/foobar`.
+    /// `xx/bin/foo` but not `bin/foobar`. Also makes --attr and --output match whole names.
     #[clap(short, long)]
     whole_name: bool,
 
@@ -320,6 +890,179 @@ struct Opts {
     /// store path are omitted. This is useful for scripts that use the output of nix-locate.
     #[clap(long)]
     minimal: bool,
+
+    /// Print the targets of symlinks, as `PATH -> TARGET`.
+    #[clap(
+        short,
+        long,
+        conflicts_with_all = [
+            "minimal",
+            "format",
+            "template",
+            "count",
+            "count_per_package",
+            "size_per_package",
+            "quiet"
+        ]
+    )]
+    long: bool,
+
+    /// Print the results in a machine-readable format instead of a table. File paths and symlink
+    /// targets are printed without any loss, even if they are not valid UTF-8: as raw bytes in
+    /// CSV and null-separated output, and as arrays of bytes in JSON if necessary. The
+    /// null-separated format only prints the absolute path of each file.
+    #[clap(long, value_enum, conflicts_with_all = ["minimal", "template"])]
+    format: Option<Format>,
+
+    /// Print each result on its own line in the given format, where the placeholders {attr},
+    /// {output}, {toplevel}, {system}, {store_path}, {path}, {type}, {size} and {target} are
+    /// replaced by the fields of the result. Write {{ and }} for literal braces.
+    #[clap(long, conflicts_with = "minimal")]
+    template: Option<Template>,
+
+    /// Sort the results by the given key. Results with the same key are printed in the order in
+    /// which they are stored in the database, which is also the order without this option.
+    #[clap(long, value_enum, value_name = "KEY")]
+    sort: Option<SortKey>,
+
+    /// Sort in descending order. Results with the same key still keep their order.
+    #[clap(long, requires = "sort")]
+    reverse: bool,
+
+    /// Stop after the first N results (after sorting, if --sort is given).
+    #[clap(long, value_name = "N")]
+    limit: Option<usize>,
+
+    /// Only print the number of results.
+    #[clap(
+        long,
+        conflicts_with_all = ["minimal", "format", "template", "count_per_package", "size_per_package"]
+    )]
+    count: bool,
+
+    /// Only print the number of results of each package.
+    #[clap(long, conflicts_with_all = ["minimal", "format", "template"])]
+    count_per_package: bool,
+
+    /// Only print the total size of the regular files among the results of each package,
+    /// with the largest packages first. Use --min-size to only count large files.
+    #[clap(
+        long,
+        conflicts_with_all = ["minimal", "format", "template", "count_per_package"]
+    )]
+    size_per_package: bool,
+
+    /// Do not print anything, only exit with status 0 if there are any results and 1 otherwise.
+    /// The search stops at the first result.
+    #[clap(
+        short,
+        long,
+        conflicts_with_all = [
+            "minimal",
+            "format",
+            "template",
+            "count",
+            "count_per_package",
+            "size_per_package",
+            "sort"
+        ]
+    )]
+    quiet: bool,
+
+    /// List all files of the packages with the attribute ATTR (such as `openssl`, which
+    /// includes all outputs) or ATTR.OUTPUT (such as `openssl.dev`) instead of searching. Without
+    /// ATTR, list the files of the package given by --hash.
+    #[clap(
+        long,
+        value_name = "ATTR[.OUTPUT]",
+        num_args = 0..=1,
+        conflicts_with_all = [
+            "pattern",
+            "regex",
+            "package",
+            "attr",
+            "output",
+            "min_size",
+            "max_size",
+            "target",
+            "type",
+            "whole_name",
+            "at_root",
+            "no_group",
+            "minimal",
+            "sort",
+            "limit",
+            "count",
+            "count_per_package",
+            "size_per_package",
+            "quiet",
+            "info",
+            "daemon"
+        ]
+    )]
+    list: Option<Option<String>>,
+
+    /// Print the package that provides the file at PATH, such as
+    /// `/nix/store/<hash>-hello-2.12.1/bin/hello`, instead of searching. Symlinks on disk that
+    /// lead into the store, such as `/run/current-system/sw/bin/hello`, are resolved. With
+    /// --minimal, only the attribute is printed.
+    #[clap(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = [
+            "pattern",
+            "list",
+            "regex",
+            "package",
+            "attr",
+            "output",
+            "HASH",
+            "min_size",
+            "max_size",
+            "target",
+            "type",
+            "whole_name",
+            "at_root",
+            "no_group",
+            "format",
+            "template",
+            "sort",
+            "limit",
+            "count",
+            "count_per_package",
+            "size_per_package",
+            "quiet",
+            "info",
+            "daemon"
+        ]
+    )]
+    owner: Option<PathBuf>,
+
+    /// Show the files listed with --list as a tree.
+    #[clap(long, requires = "list", conflicts_with_all = ["format", "template"])]
+    tree: bool,
+
+    /// Print information about the database instead of searching it, such as when it was built,
+    /// for which nixpkgs and systems, and how many packages and files it contains.
+    #[clap(long)]
+    info: bool,
+
+    /// Search a decompressed copy of the database, which is cached in
+    /// `$XDG_RUNTIME_DIR/nix-index` and created on first use. This makes repeated searches much
+    /// faster, but the cache takes several times the size of the database. The cache is
+    /// recreated when the database changes.
+    #[clap(long)]
+    cache: bool,
+
+    /// Run a daemon that loads the database into memory once and answers the searches of other
+    /// nix-locate processes over a Unix socket in `$XDG_RUNTIME_DIR/nix-index`. While the daemon
+    /// is running, nix-locate searches with the daemon instead of reading the database itself.
+    #[clap(long, conflicts_with_all = ["pattern", "info", "no_daemon"])]
+    daemon: bool,
+
+    /// Search the database directly, even if a daemon is running.
+    #[clap(long)]
+    no_daemon: bool,
 }
 
 #[derive(clap::ValueEnum, Clone, Copy, Debug)]
@@ -342,13 +1085,217 @@ impl FromStr for Color {
     }
 }
 
+/// Runs the command selected by the arguments. Returns whether anything matched.
+fn run(args: &Args) -> Result<bool> {
+    if args.info {
+        print_info(args).map(|()| true)
+    } else if let Some(attr) = &args.list {
+        list(args, attr)
+    } else if let Some(path) = &args.owner {
+        owner(args, path)
+    } else if args.daemon {
+        run_daemon(args).map(|()| true)
+    } else {
+        locate(args)
+    }
+}
+
+/// Returns the exit status for the result of `run`: like grep, 0 if anything matched, 1 if
+/// nothing matched and 2 on errors.
+fn exit_code(result: &Result<bool>) -> i32 {
+    match result {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(_) => 2,
+    }
+}
+
 fn main() {
     let args = Opts::parse();
 
     let args = process_args(args).unwrap_or_else(|e| e.exit());
 
-    if let Err(e) = locate(&args) {
+    let result = run(&args);
+    if let Err(e) = &result {
         eprintln!("error: {}", e);
-        process::exit(2);
+    }
+    process::exit(exit_code(&result));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use nix_index::package::PathOrigin;
+
+    use super::*;
+
+    fn args(flags: &[&str]) -> Args {
+        let argv = ["nix-locate", "--color", "never", "--no-daemon"]
+            .iter()
+            .chain(flags);
+        process_args(Opts::parse_from(argv)).expect("valid arguments")
+    }
+
+    fn store_path(attr: &str) -> StorePath {
+        let origin = PathOrigin {
+            attr: attr.to_string(),
+            output: "out".to_string(),
+            toplevel: true,
+            system: None,
+        };
+        let path = format!("/nix/store/010yd8jls8w4vcnql4zhjbnyp2yay5pl-{}", attr);
+        StorePath::parse(origin, &path).expect("valid store path")
+    }
+
+    fn result(attr: &str, path: &str) -> Result<(StorePath, FileTreeEntry)> {
+        let entry = FileTreeEntry {
+            path: path.as_bytes().to_vec(),
+            node: FileNode::Regular {
+                size: 10,
+                executable: true,
+            },
+        };
+        Ok((store_path(attr), entry))
+    }
+
+    /// Writes the results with the given flags, returning the result and the output.
+    fn write(
+        flags: &[&str],
+        results: impl Iterator<Item = Result<(StorePath, FileTreeEntry)>>,
+    ) -> (Result<bool>, String) {
+        let args = args(flags);
+        let search = Search::new(args.request.clone()).expect("valid pattern");
+        let mut out = Vec::new();
+        let result = write_results(&mut out, &args, &search, results);
+        (result, String::from_utf8(out).expect("output is UTF-8"))
+    }
+
+    /// Returns the first `n` columns of each line of the output.
+    fn columns(output: &str, n: usize) -> Vec<Vec<&str>> {
+        output
+            .lines()
+            .map(|line| line.split_whitespace().take(n).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_limit() {
+        // the results never end, so writing only stops if no more results are read than needed
+        let pulled = Cell::new(0);
+        let results = || {
+            std::iter::repeat_with(|| {
+                pulled.set(pulled.get() + 1);
+                result("hello", "/bin/hello")
+            })
+        };
+
+        let (matched, output) = write(&["--limit", "3", "hello"], results());
+        assert!(matched.expect("results can be written"));
+        assert_eq!(output.lines().count(), 3);
+        assert_eq!(pulled.replace(0), 3);
+
+        let (matched, output) = write(&["--limit", "2", "--count", "hello"], results());
+        assert!(matched.expect("results can be written"));
+        assert_eq!(output, "2\n");
+        assert_eq!(pulled.replace(0), 2);
+
+        let (matched, output) = write(&["--quiet", "hello"], results());
+        assert!(matched.expect("results can be written"));
+        assert_eq!(output, "");
+        assert_eq!(pulled.replace(0), 1);
+    }
+
+    #[test]
+    fn test_count() {
+        let results = || {
+            vec![
+                result("hello", "/bin/hello"),
+                result("world", "/bin/world"),
+                result("hello", "/share/hello"),
+            ]
+            .into_iter()
+        };
+
+        let (matched, output) = write(&["--count", "o"], results());
+        assert!(matched.expect("results can be written"));
+        assert_eq!(output, "3\n");
+
+        // packages are listed in the order in which they were first found
+        let (matched, output) = write(&["--count-per-package", "o"], results());
+        assert!(matched.expect("results can be written"));
+        assert_eq!(
+            columns(&output, 2),
+            [["hello.out", "2"], ["world.out", "1"]]
+        );
+
+        let (matched, output) = write(&["--size-per-package", "o"], results());
+        assert!(matched.expect("results can be written"));
+        assert_eq!(
+            columns(&output, 2),
+            [["hello.out", "20"], ["world.out", "10"]]
+        );
+
+        // without results, the count is still written but nothing matched
+        let (matched, output) = write(&["--count", "o"], std::iter::empty());
+        assert!(!matched.expect("results can be written"));
+        assert_eq!(output, "0\n");
+        let (matched, output) = write(&["--count-per-package", "o"], std::iter::empty());
+        assert!(!matched.expect("results can be written"));
+        assert_eq!(output, "");
+    }
+
+    /// A database directory as used by `--db`, which is removed again when this is dropped.
+    struct TestDatabase {
+        dir: PathBuf,
+    }
+
+    impl TestDatabase {
+        /// Creates a database with a package for each result.
+        fn new(name: &str, results: Vec<Result<(StorePath, FileTreeEntry)>>) -> TestDatabase {
+            let dir = env::temp_dir().join(format!("nix-locate-test-{}-{}", process::id(), name));
+            fs::create_dir_all(&dir).expect("directory can be created");
+            let database = TestDatabase { dir };
+            let mut writer = database::Writer::create(database.dir.join("files"), 3)
+                .expect("database can be created");
+            for result in results {
+                let (store_path, entry) = result.expect("valid result");
+                let tree = FileTree::from_list(vec![entry]);
+                writer
+                    .add(store_path, tree, b"")
+                    .expect("package can be added");
+            }
+            writer.finish().expect("database can be finished");
+            database
+        }
+    }
+
+    impl Drop for TestDatabase {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn test_exit_code() {
+        let database = TestDatabase::new("exit-code", vec![result("hello", "/bin/hello")]);
+        let db = database.dir.to_str().expect("path is UTF-8");
+
+        let status = |flags: &[&str]| exit_code(&run(&args(flags)));
+        assert_eq!(status(&["--db", db, "--quiet", "bin/hello"]), 0);
+        assert_eq!(status(&["--db", db, "--quiet", "bin/world"]), 1);
+        // an invalid pattern and a missing database are errors
+        assert_eq!(status(&["--db", db, "--quiet", "--regex", "("]), 2);
+        let missing = database.dir.join("missing");
+        let missing = missing.to_str().expect("path is UTF-8");
+        assert_eq!(status(&["--db", missing, "--quiet", "bin/hello"]), 2);
+
+        // an error is reported even if there were results before it
+        let error = Error::StartDaemon {
+            source: io::Error::other("failed"),
+        };
+        let results = vec![result("hello", "/bin/hello"), Err(error)].into_iter();
+        let (matched, _) = write(&["--count", "hello"], results);
+        assert_eq!(exit_code(&matched), 2);
     }
 }
@@ -0,0 +1,273 @@
+//! A cache of the decompressed contents of a database.
+//!
+//! Searching a database requires decompressing and decoding all of its frames, which dominates
+//! the time of a search. Tools that search the same database many times in a row can instead
+//! keep the decoded entries of all frames in a cache file, which is memory-mapped and searched
+//! directly (see `database::Reader::use_cache`). Long-running processes can also keep the
+//! decoded frames in memory instead (see `database::Reader::load_into_memory`).
+//!
+//! The cache file starts with the magic `NIXC` and a version, followed by the decoded frames
+//! (in the same line-based format that `frcode::Decoder` produces) and a bincode-encoded header
+//! with the locations of the frames. The last 8 bytes of the file hold the length of the header.
+//!
+//! The decoded entries are several times larger than the compressed database, so by default the
+//! cache is stored in `$XDG_RUNTIME_DIR`, which is usually a tmpfs that is cleared on logout.
+use std::fs::{self, File, Metadata};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::{mem, process};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+
+use crate::util;
+
+/// The magic at the start of every cache file.
+const CACHE_MAGIC: &[u8] = b"NIXC";
+
+/// The version of the format of the cache file. Caches with a different version are recreated.
+const CACHE_VERSION: u64 = 1;
+
+/// The length of the magic and the version at the start of the cache file.
+const HEADER_OFFSET: u64 = 12;
+
+/// Identifies the database for which a cache was created.
+///
+/// Since `nix-index` replaces a database by renaming a new file over it, the inode changes
+/// whenever the database is rebuilt. The frame checksums additionally guard against databases
+/// that were modified in place.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Key {
+    device: u64,
+    inode: u64,
+    len: u64,
+    modified: (i64, i64),
+    checksums: Vec<u32>,
+}
+
+impl Key {
+    /// Returns the key of a database with the given file metadata and frame checksums.
+    pub fn new(metadata: &Metadata, checksums: Vec<u32>) -> Key {
+        Key {
+            device: metadata.dev(),
+            inode: metadata.ino(),
+            len: metadata.len(),
+            modified: (metadata.mtime(), metadata.mtime_nsec()),
+            checksums,
+        }
+    }
+}
+
+/// The header of a cache file, which is stored at the end of the file.
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    /// The database from which the cache was created.
+    key: Key,
+    /// The offsets and lengths of the decoded frames.
+    frames: Vec<(u64, u64)>,
+}
+
+/// The decoded frames of a database, either memory-mapped from a cache file or held in memory.
+pub struct Cache {
+    data: Data,
+    frames: Vec<Range<usize>>,
+}
+
+/// The storage of the decoded frames of a `Cache`.
+enum Data {
+    Mapped(Mmap),
+    Memory(Vec<u8>),
+}
+
+impl Cache {
+    /// Opens the cache at the given path.
+    ///
+    /// Returns `None` if there is no cache at that path, or if the cache is invalid or was
+    /// created for a different database than the one identified by `key`.
+    pub fn open(path: &Path, key: &Key) -> io::Result<Option<Cache>> {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let Some(header) = read_header(&mut file)? else {
+            return Ok(None);
+        };
+        if &header.key != key {
+            return Ok(None);
+        }
+
+        let len = file.metadata()?.len();
+        let mut frames = Vec::with_capacity(header.frames.len());
+        for (offset, frame_len) in header.frames {
+            match offset.checked_add(frame_len) {
+                Some(end) if end <= len => frames.push(offset as usize..end as usize),
+                _ => return Ok(None),
+            }
+        }
+
+        // SAFETY: cache files are never modified after they have been created, they are only
+        // replaced by renaming a new file over them. So the mapped contents cannot change.
+        let map = unsafe { Mmap::map(&file)? };
+        Ok(Some(Cache {
+            data: Data::Mapped(map),
+            frames,
+        }))
+    }
+
+    /// Creates a cache that holds the decoded frames in memory, where `frames` are the ranges
+    /// of the frames in `data`.
+    pub(crate) fn in_memory(data: Vec<u8>, frames: Vec<Range<usize>>) -> Cache {
+        Cache {
+            data: Data::Memory(data),
+            frames,
+        }
+    }
+
+    /// Returns the decoded entries of the frame with the given index.
+    pub fn frame(&self, index: usize) -> &[u8] {
+        let data = match &self.data {
+            Data::Mapped(map) => &map[..],
+            Data::Memory(data) => &data[..],
+        };
+        &data[self.frames[index].clone()]
+    }
+
+    /// Returns the number of frames in the cache.
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Returns true if the cache contains no frames.
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+}
+
+/// Reads the header of a cache file, returning `None` if the file is not a valid cache file.
+fn read_header(file: &mut File) -> io::Result<Option<Header>> {
+    let mut magic = [0u8; 4];
+    if file.read_exact(&mut magic).is_err() || magic != CACHE_MAGIC {
+        return Ok(None);
+    }
+    if file.read_u64::<LittleEndian>()? != CACHE_VERSION {
+        return Ok(None);
+    }
+
+    let len = file.metadata()?.len();
+    if len < HEADER_OFFSET + 8 {
+        return Ok(None);
+    }
+    file.seek(SeekFrom::Start(len - 8))?;
+    let header_len = file.read_u64::<LittleEndian>()?;
+    let Some(header_offset) = (len - 8).checked_sub(header_len) else {
+        return Ok(None);
+    };
+    if header_offset < HEADER_OFFSET {
+        return Ok(None);
+    }
+
+    let mut header = vec![0; header_len as usize];
+    file.seek(SeekFrom::Start(header_offset))?;
+    file.read_exact(&mut header)?;
+    Ok(
+        bincode::serde::decode_from_slice(&header, bincode::config::standard())
+            .ok()
+            .map(|(header, _)| header),
+    )
+}
+
+/// A writer for creating a new cache file.
+///
+/// Like the database, the cache is first written to a temporary file, which replaces the cache
+/// once it is finished. This way, concurrent processes never see a partially written cache.
+pub struct Writer {
+    /// The file to which the cache is written. Will be set to `None` when it has been finished.
+    file: Option<BufWriter<File>>,
+    /// The path of the cache.
+    path: PathBuf,
+    /// The path of the temporary file that is written until the cache is finished.
+    temp_path: PathBuf,
+    /// The offsets and lengths of the frames written so far.
+    frames: Vec<(u64, u64)>,
+    /// The offset at which the next frame will be written.
+    offset: u64,
+}
+
+// Remove the temporary file if the cache was not finished.
+impl Drop for Writer {
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+impl Writer {
+    /// Creates a new cache at the given path.
+    pub fn create(path: &Path) -> io::Result<Writer> {
+        let path = path.to_path_buf();
+        let mut temp_path = path.clone().into_os_string();
+        temp_path.push(format!(".{}.tmp", process::id()));
+        let temp_path = PathBuf::from(temp_path);
+
+        let mut file = BufWriter::new(File::create(&temp_path)?);
+        file.write_all(CACHE_MAGIC)?;
+        file.write_u64::<LittleEndian>(CACHE_VERSION)?;
+        Ok(Writer {
+            file: Some(file),
+            path,
+            temp_path,
+            frames: Vec::new(),
+            offset: HEADER_OFFSET,
+        })
+    }
+
+    /// Adds the decoded entries of the next frame to the cache.
+    pub fn add_frame(&mut self, data: &[u8]) -> io::Result<()> {
+        let file = self.file.as_mut().expect("not finished yet");
+        file.write_all(data)?;
+        self.frames.push((self.offset, data.len() as u64));
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+
+    /// Writes the header for the database identified by `key`, replaces any existing cache at
+    /// the path of the cache and opens the new cache.
+    pub fn finish(mut self, key: Key) -> io::Result<Cache> {
+        let file = self.file.take().expect("not finished yet");
+        let header = Header {
+            key,
+            frames: mem::take(&mut self.frames),
+        };
+        let result =
+            write_header(file, &header).and_then(|()| fs::rename(&self.temp_path, &self.path));
+        if let Err(e) = result {
+            let _ = fs::remove_file(&self.temp_path);
+            return Err(e);
+        }
+
+        Cache::open(&self.path, &header.key)?
+            .ok_or_else(|| io::Error::other("the cache was replaced while it was being created"))
+    }
+}
+
+/// Writes the header and its length to the end of a cache file.
+fn write_header(mut file: BufWriter<File>, header: &Header) -> io::Result<()> {
+    let header = bincode::serde::encode_to_vec(header, bincode::config::standard())
+        .map_err(io::Error::other)?;
+    file.write_all(&header)?;
+    file.write_u64::<LittleEndian>(header.len() as u64)?;
+    file.flush()
+}
+
+/// Returns the default path of the cache for the database at the given path, in the directory
+/// `nix-index` below `$XDG_RUNTIME_DIR`. The directory is created if it does not exist.
+///
+/// An error is returned if `$XDG_RUNTIME_DIR` is not set.
+pub fn default_path(database: &Path) -> io::Result<PathBuf> {
+    util::runtime_file(database, "files")
+}
@@ -0,0 +1,377 @@
+//! A daemon that answers the searches of nix-locate over a Unix socket.
+//!
+//! Every nix-locate process needs to decompress the database again. The daemon instead loads
+//! the database into memory once and answers searches from clients, such as nix-locate itself,
+//! which uses the daemon if one is running for its database. The daemon reloads the database
+//! when the database file is replaced, for example by running nix-index.
+//!
+//! The protocol is line-based JSON. A client connects to the socket and sends a single
+//! `locate::Request` on one line, for example:
+//!
+//! ```json
+//! {"pattern": "bin/hello", "whole_name": true, "types": ["x"]}
+//! ```
+//!
+//! The daemon answers with one `Response` per line: a `match` for every matching file, followed
+//! by either `"done"` or an `error`. Then the daemon closes the connection. Since file paths and
+//! symlink targets are not necessarily valid UTF-8, they are sent as arrays of bytes.
+use std::fs::{self, Metadata};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::database::{self, Reader};
+use crate::files::{FileNode, FileTreeEntry};
+use crate::locate::{Request, Search};
+use crate::package::StorePath;
+use crate::util;
+
+/// The number of clients that the daemon serves at the same time.
+const MAX_CLIENTS: usize = 8;
+
+/// How long the daemon waits for the request of a client that has connected.
+///
+/// Since only `MAX_CLIENTS` clients are served at a time, clients that never send a request
+/// must not block the daemon.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A single line of the response of the daemon.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Response {
+    /// A file that matches the request.
+    Match {
+        store_path: StorePath,
+        #[serde(with = "serde_bytes")]
+        path: Vec<u8>,
+        node: FileNode<()>,
+    },
+    /// The search failed. This is the last response.
+    Error { message: String },
+    /// All matches have been sent. This is the last response.
+    Done,
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("communicating with the daemon failed: {0}")]
+    Io(#[from] io::Error),
+    #[error("the daemon sent an invalid response: {0}")]
+    Protocol(#[from] serde_json::Error),
+    #[error("the daemon failed to search the database: {0}")]
+    Search(String),
+    #[error("the daemon closed the connection before the search was complete")]
+    Incomplete,
+}
+
+/// Returns the path of the socket of the daemon for the database at the given path, in the
+/// directory `nix-index` below `$XDG_RUNTIME_DIR`.
+pub fn socket_path(database: &Path) -> io::Result<PathBuf> {
+    util::runtime_file(database, "daemon")
+}
+
+/// Sends a request to the daemon listening on the given socket.
+///
+/// Returns `None` if no daemon is listening on the socket. Otherwise, returns an iterator over
+/// the matches found by the daemon.
+pub fn query(socket: &Path, request: &Request) -> Result<Option<Matches>, Error> {
+    let stream = match UnixStream::connect(socket) {
+        Ok(stream) => stream,
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+            ) =>
+        {
+            return Ok(None)
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut line = serde_json::to_vec(request)?;
+    line.push(b'\n');
+    (&stream).write_all(&line)?;
+    Ok(Some(Matches {
+        lines: BufReader::new(stream).lines(),
+        done: false,
+    }))
+}
+
+/// An iterator over the matches sent by the daemon, created by `query`.
+pub struct Matches {
+    lines: io::Lines<BufReader<UnixStream>>,
+    /// Set when the last response has been received.
+    done: bool,
+}
+
+impl Iterator for Matches {
+    type Item = Result<(StorePath, FileTreeEntry), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let response = match self.lines.next() {
+            None => Err(Error::Incomplete),
+            Some(line) => line
+                .map_err(Error::from)
+                .and_then(|line| Ok(serde_json::from_str(&line)?)),
+        };
+        match response {
+            Ok(Response::Match {
+                store_path,
+                path,
+                node,
+            }) => Some(Ok((store_path, FileTreeEntry { path, node }))),
+            Ok(Response::Done) => {
+                self.done = true;
+                None
+            }
+            Ok(Response::Error { message }) => {
+                self.done = true;
+                Some(Err(Error::Search(message)))
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Binds the socket at the given path.
+///
+/// If the socket already exists but no daemon is listening on it anymore (because the daemon
+/// was killed), the stale socket is replaced.
+pub fn bind(socket: &Path) -> io::Result<UnixListener> {
+    match UnixListener::bind(socket) {
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+            if UnixStream::connect(socket).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("a daemon is already listening on {}", socket.display()),
+                ));
+            }
+            fs::remove_file(socket)?;
+            UnixListener::bind(socket)
+        }
+        result => result,
+    }
+}
+
+/// Identifies a version of the database file, to notice when the database was replaced.
+type FileId = (u64, u64, u64, i64, i64);
+
+fn file_id(metadata: &Metadata) -> FileId {
+    (
+        metadata.dev(),
+        metadata.ino(),
+        metadata.len(),
+        metadata.mtime(),
+        metadata.mtime_nsec(),
+    )
+}
+
+/// A database that is loaded into memory and reloaded when the database file is replaced.
+///
+/// This is the database that the daemon searches, but it is also useful for other long-running
+/// processes that answer searches.
+pub struct Database {
+    path: PathBuf,
+    /// The version of the database file that was loaded.
+    file_id: FileId,
+    reader: Reader,
+}
+
+impl Database {
+    /// Opens the database file at the given path and loads it into memory.
+    pub fn load(path: &Path) -> Result<Database, database::Error> {
+        // If the file is replaced after this, we will just load the new file again later.
+        let file_id = file_id(&fs::metadata(path)?);
+        let mut reader = Reader::open(path)?;
+        reader.load_into_memory()?;
+        Ok(Database {
+            path: path.to_path_buf(),
+            file_id,
+            reader,
+        })
+    }
+
+    /// Returns a reader for the database, reloading the database first if the database file
+    /// was replaced since it was loaded.
+    pub fn reader(&mut self) -> Result<Reader, database::Error> {
+        if file_id(&fs::metadata(&self.path)?) != self.file_id {
+            *self = Database::load(&self.path)?;
+        }
+        Ok(self.reader.clone())
+    }
+}
+
+/// Loads the database at the given path into memory and answers searches from the clients
+/// that connect to the socket. A fixed number of threads (`MAX_CLIENTS`) serve the clients, so
+/// further clients wait until one of the current searches is done.
+///
+/// This only returns if the database cannot be loaded or accepting a client fails.
+pub fn serve(listener: UnixListener, database: &Path) -> Result<(), database::Error> {
+    let database = Mutex::new(Database::load(database)?);
+    let listeners = (0..MAX_CLIENTS)
+        .map(|_| listener.try_clone())
+        .collect::<io::Result<Vec<_>>>()?;
+    thread::scope(|scope| {
+        let workers = listeners
+            .into_iter()
+            .map(|listener| scope.spawn(|| accept(listener, &database)))
+            .collect::<Vec<_>>();
+        for worker in workers {
+            worker
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic))?;
+        }
+        Ok(())
+    })
+}
+
+/// Accepts clients from the listener and answers their requests one after another.
+fn accept(listener: UnixListener, database: &Mutex<Database>) -> io::Result<()> {
+    for stream in listener.incoming() {
+        // If the client went away, there is nobody left to report the error to.
+        let _ = handle(stream?, database);
+    }
+    Ok(())
+}
+
+/// Answers the request of a single client.
+fn handle(stream: UnixStream, database: &Mutex<Database>) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut request = String::new();
+    BufReader::new(&stream).read_line(&mut request)?;
+    let mut output = BufWriter::new(&stream);
+    let last = search(&request, database, &mut output)?;
+    write_response(&mut output, &last)?;
+    output.flush()
+}
+
+/// Runs the search of a request, writing all matches to `output`. Returns the response that
+/// ends the search.
+fn search(
+    request: &str,
+    database: &Mutex<Database>,
+    output: &mut impl Write,
+) -> io::Result<Response> {
+    let error = |message: String| Ok(Response::Error { message });
+
+    let request = match serde_json::from_str(request) {
+        Ok(request) => request,
+        Err(e) => return error(format!("invalid request: {}", e)),
+    };
+    let search = match Search::new(request) {
+        Ok(search) => search,
+        Err(e) => return error(e.to_string()),
+    };
+    let reader = database
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .reader();
+    let results = match reader.and_then(|reader| search.run(reader)) {
+        Ok(results) => results,
+        Err(e) => return error(e.to_string()),
+    };
+
+    for result in results {
+        let (store_path, FileTreeEntry { path, node }) = match result {
+            Ok(v) => v,
+            Err(e) => return error(e.to_string()),
+        };
+        write_response(
+            output,
+            &Response::Match {
+                store_path,
+                path,
+                node,
+            },
+        )?;
+    }
+    Ok(Response::Done)
+}
+
+fn write_response(output: &mut impl Write, response: &Response) -> io::Result<()> {
+    serde_json::to_writer(&mut *output, response)?;
+    output.write_all(b"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{bin_tree, store_path, temp_path, TestDatabase};
+
+    fn names(matches: Matches) -> Vec<(String, Vec<u8>)> {
+        matches
+            .map(|r| {
+                let (pkg, entry) = r.expect("entry can be received");
+                (pkg.name().into_owned(), entry.path)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_daemon() {
+        let database = TestDatabase::new(
+            "daemon-database",
+            &[(store_path("hello"), bin_tree(&["hello", "world"]))],
+        );
+        let socket = temp_path("daemon.sock");
+
+        let request = Request {
+            pattern: "/bin/hello".to_string(),
+            whole_name: true,
+            at_root: true,
+            ..Request::default()
+        };
+        assert!(query(&socket, &request)
+            .expect("query can be sent")
+            .is_none());
+
+        let listener = bind(&socket).expect("socket can be bound");
+        let path = database.path().to_path_buf();
+        thread::spawn(move || serve(listener, &path));
+        let results = query(&socket, &request)
+            .expect("query can be sent")
+            .expect("daemon is running");
+        assert_eq!(
+            names(results),
+            vec![("hello".to_string(), b"/bin/hello".to_vec())]
+        );
+
+        // more clients than the daemon serves at a time are answered one after another
+        let clients = (0..MAX_CLIENTS * 2)
+            .map(|_| {
+                query(&socket, &request)
+                    .expect("query can be sent")
+                    .expect("daemon is running")
+            })
+            .collect::<Vec<_>>();
+        for results in clients {
+            assert_eq!(names(results).len(), 1);
+        }
+
+        let invalid = Request {
+            pattern: "(".to_string(),
+            regex: true,
+            ..Request::default()
+        };
+        let mut results = query(&socket, &invalid)
+            .expect("query can be sent")
+            .expect("daemon is running");
+        assert!(matches!(results.next(), Some(Err(Error::Search(_)))));
+        assert!(results.next().is_none());
+
+        fs::remove_file(&socket).expect("socket can be removed");
+    }
+}
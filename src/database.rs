@@ -1,47 +1,293 @@
-use std::fs::File;
+use std::collections::{hash_map, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fs::{self, File};
 /// Creating and searching file databases.
 ///
 /// This module implements an abstraction for creating an index of files with meta information
 /// and searching that index for paths matching a specific pattern.
-use std::io::{self, BufReader, BufWriter, Read, Seek, Write};
-use std::path::Path;
+///
+/// A database file starts with the file magic and the format version. In version 2 of the
+/// format, this header is followed by a sequence of independent zstd frames, each holding the
+/// frcode-encoded entries of one or more complete packages. The frames are followed by a footer
+/// listing the location of each frame, so that frames can be decompressed and searched in
+/// parallel. In version 1, the header was followed by a single zstd stream.
+///
+/// Between the last frame and the footer, a version 2 database may contain a basename index,
+/// which maps the paths of files directly inside some directories (like `/bin`) to the frames
+/// containing them. This allows looking up exact paths without searching all frames.
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{cmp, mem};
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use grep;
 use grep::matcher::{LineMatchKind, Match, Matcher, NoError};
 use memchr::{memchr, memrchr};
+use rayon::prelude::*;
 use regex::bytes::Regex;
-use regex_syntax::ast::{AssertionKind, Ast, Literal};
+use regex_syntax::ast::{AssertionKind, Ast, GroupKind, Literal, RepetitionKind, RepetitionRange};
+use serde::{Deserialize, Serialize};
 use serde_json;
 use thiserror::Error;
 use zstd;
+use zstd::bulk::Compressor;
+use zstd::dict::EncoderDictionary;
+use zstd::zstd_safe::CParameter;
 
-use crate::files::{FileTree, FileTreeEntry};
+use crate::cache::{self, Cache};
+use crate::files::{FileNode, FileTree, FileTreeEntry, Resolved, MAX_SYMLINKS};
 use crate::frcode;
 use crate::package::StorePath;
 
-/// The version of the database format supported by this nix-index version.
+/// The version of the database format written by this nix-index version.
 ///
 /// This should be updated whenever you make an incompatible change to the database format.
-const FORMAT_VERSION: u64 = 1;
+const FORMAT_VERSION: u64 = 2;
+
+/// The oldest version of the database format that can still be read.
+const MIN_FORMAT_VERSION: u64 = 1;
 
 /// The magic for nix-index database files, used to ensure that the file we're passed is
 /// actually a file generated by nix-index.
+///
+/// The magic is also written as the very last bytes of the file, so that truncated
+/// files can be detected.
 const FILE_MAGIC: &[u8] = b"NIXI";
 
+/// The length of the header (file magic and format version) at the start of each database.
+const HEADER_LEN: u64 = 12;
+
+/// The length of the trailer (footer length, footer checksum and file magic) at the end of
+/// each database.
+const TRAILER_LEN: u64 = 16;
+
+/// The uncompressed size after which a frame is closed and a new one is started.
+///
+/// Frames are only ever split at package boundaries, so frames may be bigger than this.
+/// Smaller frames allow for more parallelism when searching, but compress worse.
+const FRAME_SIZE: usize = 4 * 1024 * 1024;
+
+/// The number of bits of the trigram filter of each frame.
+const TRIGRAM_FILTER_BITS: u64 = 1 << 17;
+
+/// The directories whose entries are added to the basename index by default.
+pub const DEFAULT_INDEXED_DIRECTORIES: &[&str] = &["/bin", "/sbin", "/lib"];
+
+/// The location of a single zstd frame inside the database file.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Frame {
+    /// The offset of the first byte of the frame, relative to the start of the file.
+    offset: u64,
+    /// The length of the compressed frame in bytes.
+    len: u64,
+    /// The CRC-32 checksum of the compressed frame.
+    checksum: u32,
+}
+
+/// Information about how a database was built.
+///
+/// The metadata is stored in the footer of the database and can be retrieved with
+/// `Reader::metadata`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Metadata {
+    /// The name and version of the program that built the database. Filled in by the writer.
+    pub generator: String,
+    /// The time at which the database was built, in seconds since the unix epoch. Filled in
+    /// by the writer.
+    pub created: u64,
+    /// The nixpkgs for which the database was built, as accepted by `nix-env -f`.
+    pub nixpkgs: Option<String>,
+    /// The version of nixpkgs (`lib.version`) for which the database was built, if known.
+    pub nixpkgs_version: Option<String>,
+    /// The systems of the packages in the database. Filled in by the writer.
+    pub systems: Vec<String>,
+    /// The package sets that were queried in addition to the top-level packages of nixpkgs.
+    pub extra_scopes: Vec<String>,
+    /// Only files whose path starts with this prefix were added to the database.
+    pub filter_prefix: String,
+    /// The number of packages in the database. Filled in by the writer.
+    pub packages: u64,
+    /// The number of file entries in the database. Filled in by the writer.
+    pub entries: u64,
+}
+
+/// The location of the basename index inside the database file.
+///
+/// The index is a hash table: it starts with `buckets + 1` little-endian u64 offsets (relative
+/// to the start of the index) of the buckets, followed by the buckets themselves. Each bucket is
+/// a bincode-encoded list of paths together with the indices of the frames that contain them.
+/// Paths are assigned to buckets by their CRC-32 checksum.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BasenameIndex {
+    /// The directories whose direct children are part of the index.
+    directories: Vec<String>,
+    /// The offset of the index, relative to the start of the file.
+    offset: u64,
+    /// The length of the index in bytes.
+    len: u64,
+    /// The number of buckets of the hash table.
+    buckets: u64,
+    /// The CRC-32 checksum of the index.
+    checksum: u32,
+}
+
+impl BasenameIndex {
+    /// Returns the bucket in which the given path is stored.
+    fn bucket(&self, path: &[u8]) -> u64 {
+        u64::from(crc32fast::hash(path)) % self.buckets
+    }
+
+    /// Returns true if the given path is part of the index, which is the case if it is a
+    /// direct child of one of the indexed directories.
+    fn covers(&self, path: &[u8]) -> bool {
+        indexed_directory(&self.directories, path).is_some()
+    }
+}
+
+/// The location of the trigram filters inside the database file.
+///
+/// For each frame, there is a filter with a bit for each possible trigram (three consecutive
+/// bytes), which is set if a path stored in the frame contains the trigram. Different trigrams
+/// may share the same bit. If the bit of any trigram of a literal is not set, no path in the frame
+/// can contain that literal, so the frame doesn't need to be searched for patterns requiring it.
+///
+/// The filters of all frames are stored one after another, in the order of the frames.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TrigramFilters {
+    /// The offset of the filters, relative to the start of the file.
+    offset: u64,
+    /// The number of bits of the filter of each frame.
+    bits: u64,
+    /// The CRC-32 checksum of the filters.
+    checksum: u32,
+}
+
+/// The location of the zstd dictionary inside the database file.
+///
+/// The dictionary is trained on the entries of the first frames, and stored before the first
+/// frame.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Dictionary {
+    /// The offset of the dictionary, relative to the start of the file.
+    offset: u64,
+    /// The length of the dictionary in bytes.
+    len: u64,
+    /// The CRC-32 checksum of the dictionary.
+    checksum: u32,
+}
+
+/// Returns the bit for the given trigram in a trigram filter with `bits` bits.
+fn trigram_bit(trigram: &[u8], bits: u64) -> u64 {
+    let value = u32::from(trigram[0]) << 16 | u32::from(trigram[1]) << 8 | u32::from(trigram[2]);
+    (u64::from(value.wrapping_mul(0x9e37_79b1)) * bits) >> 32
+}
+
+/// Returns the indexed directory of which `path` is a direct child, if any.
+fn indexed_directory<'a>(directories: &'a [String], path: &[u8]) -> Option<&'a String> {
+    let slash = memrchr(b'/', path)?;
+    if slash + 1 == path.len() {
+        return None;
+    }
+    directories
+        .iter()
+        .find(|dir| dir.as_bytes() == &path[..slash])
+}
+
+/// The footer of a database, which is stored after the last frame.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Footer {
+    /// Information about how the database was built.
+    metadata: Metadata,
+    /// All frames of the database, in the order in which they were written.
+    frames: Vec<Frame>,
+    /// The basename index, if the database has one.
+    basename_index: Option<BasenameIndex>,
+    /// The trigram filters of the frames, if the database has them.
+    trigram_filters: Option<TrigramFilters>,
+    /// The zstd dictionary with which all frames were compressed, if any.
+    dictionary: Option<Dictionary>,
+}
+
+/// The order in which a `Writer` writes packages to the database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PackageOrder {
+    /// Packages are written in the order in which they are added.
+    #[default]
+    Added,
+    /// Packages are sorted by the hash of their store path.
+    Hash,
+    /// Packages are sorted by their attribute path, output and system.
+    Attr,
+}
+
 /// A writer for creating a new file database.
+///
+/// The database is first written to a temporary file next to its final path, and only moved
+/// to the final path by `finish`. This way, an existing database at that path stays intact
+/// until the new database is complete.
 pub struct Writer {
-    /// The encoder used to compress the database. Will be set to `None` when the value
-    /// is dropped.
-    writer: Option<BufWriter<zstd::Encoder<'static, File>>>,
+    /// The file to which the database is written. Will be set to `None` when the database
+    /// has been finished.
+    file: Option<BufWriter<File>>,
+    /// The path at which the database is created.
+    path: PathBuf,
+    /// The path of the temporary file that is written until the database is finished.
+    temp_path: PathBuf,
+    /// The zstd compression level for frames.
+    level: i32,
+    /// The frcode-encoded entries of the frame that is currently being filled.
+    frame: Vec<u8>,
+    /// The uncompressed size after which a frame is closed (`FRAME_SIZE`, except in tests).
+    frame_size: usize,
+    /// Frames that are complete but have not been compressed and written to the file yet.
+    pending: Vec<Vec<u8>>,
+    /// The number of pending frames that are compressed in parallel (the number of CPUs,
+    /// except in tests).
+    parallel_frames: usize,
+    /// The footer, which records the frames written so far.
+    footer: Footer,
+    /// The systems of all packages added so far.
+    systems: BTreeSet<String>,
+    /// The number of packages added so far.
+    packages: u64,
+    /// The number of file entries added so far.
+    entries: u64,
+    /// The offset in the file at which the next frame will be written.
+    offset: u64,
+    /// The directories whose direct children are added to the basename index.
+    indexed_directories: Vec<String>,
+    /// The paths for the basename index, with the indices of the frames that contain them.
+    basenames: HashMap<Vec<u8>, BTreeSet<u32>>,
+    /// The trigram filter of the frame that is currently being filled.
+    trigram_filter: Vec<u8>,
+    /// The trigram filters of all previous frames.
+    trigram_filters: Vec<u8>,
+    /// The maximum size of the zstd dictionary to train, or 0 if no dictionary should be used.
+    dictionary_size: usize,
+    /// The trained zstd dictionary, once the first frames have been written.
+    dictionary: Option<Vec<u8>>,
+    /// The sizes of the frcode-encoded packages in the frames that have not been written yet,
+    /// which are used as samples for training the dictionary.
+    samples: Vec<usize>,
+    /// Whether to enable long distance matching when compressing frames.
+    long_distance_matching: bool,
+    /// The order in which packages are written.
+    order: PackageOrder,
+    /// The packages that were added but not written yet, if they are not written in the
+    /// order in which they are added.
+    buffered: Vec<(StorePath, Vec<FileTreeEntry>)>,
+    /// The creation time to record in the metadata, if it should not be the current time.
+    created: Option<u64>,
 }
 
-// We need to make sure that the encoder is `finish`ed in all cases, so we need
-// a custom Drop.
+// If the writer is dropped without calling `finish`, the database is incomplete. We remove
+// the temporary file in that case, so that any previous database stays in place.
 impl Drop for Writer {
     fn drop(&mut self) {
-        if self.writer.is_some() {
-            self.finish_encoder().expect("failed to flush database");
+        if self.file.take().is_some() {
+            let _ = fs::remove_file(&self.temp_path);
         }
     }
 }
@@ -49,18 +295,98 @@ impl Drop for Writer {
 impl Writer {
     /// Creates a new database at the given path with the specified zstd compression level
     /// (currently, supported values range from 0 to 22).
+    ///
+    /// The database is written to the file `<path>.tmp` until it is finished.
     pub fn create<P: AsRef<Path>>(path: P, level: i32) -> io::Result<Writer> {
-        let mut file = File::create(path)?;
+        let path = path.as_ref().to_path_buf();
+        let mut temp_path = path.clone().into_os_string();
+        temp_path.push(".tmp");
+        let temp_path = PathBuf::from(temp_path);
+
+        let mut file = BufWriter::new(File::create(&temp_path)?);
         file.write_all(FILE_MAGIC)?;
         file.write_u64::<LittleEndian>(FORMAT_VERSION)?;
-        let mut encoder = zstd::Encoder::new(file, level)?;
-        encoder.multithread(num_cpus::get() as u32)?;
 
         Ok(Writer {
-            writer: Some(BufWriter::new(encoder)),
+            file: Some(file),
+            path,
+            temp_path,
+            level,
+            frame: Vec::new(),
+            frame_size: FRAME_SIZE,
+            pending: Vec::new(),
+            parallel_frames: num_cpus::get(),
+            footer: Footer::default(),
+            systems: BTreeSet::new(),
+            packages: 0,
+            entries: 0,
+            offset: HEADER_LEN,
+            indexed_directories: DEFAULT_INDEXED_DIRECTORIES
+                .iter()
+                .map(|dir| dir.to_string())
+                .collect(),
+            basenames: HashMap::new(),
+            trigram_filter: vec![0; (TRIGRAM_FILTER_BITS / 8) as usize],
+            trigram_filters: Vec::new(),
+            dictionary_size: 0,
+            dictionary: None,
+            samples: Vec::new(),
+            long_distance_matching: false,
+            order: PackageOrder::Added,
+            buffered: Vec::new(),
+            created: None,
         })
     }
 
+    /// Sets the metadata that will be stored in the database.
+    ///
+    /// The fields that are documented as being filled in by the writer are overwritten when
+    /// the database is finished.
+    pub fn set_metadata(&mut self, metadata: Metadata) {
+        self.footer.metadata = metadata;
+    }
+
+    /// Sets the directories whose direct children are added to the basename index (by default,
+    /// `DEFAULT_INDEXED_DIRECTORIES`). Directories are given as absolute paths without a trailing
+    /// slash, like `/bin`. If the list is empty, no basename index is written.
+    ///
+    /// This must be called before any package is added.
+    pub fn set_indexed_directories(&mut self, directories: Vec<String>) {
+        self.indexed_directories = directories;
+    }
+
+    /// Enables compressing all frames with a zstd dictionary of at most the given size in bytes,
+    /// which is trained on the first `100 * size` bytes of entries and stored in the database.
+    /// A size of 0 disables the dictionary (the default).
+    ///
+    /// This must be called before any package is added.
+    pub fn set_dictionary_size(&mut self, size: usize) {
+        self.dictionary_size = size;
+    }
+
+    /// Enables or disables zstd's long distance matching when compressing frames.
+    pub fn set_long_distance_matching(&mut self, enable: bool) {
+        self.long_distance_matching = enable;
+    }
+
+    /// Sets the order in which packages are written to the database.
+    ///
+    /// With an order other than `PackageOrder::Added`, the written database does not depend on
+    /// the order in which packages are added. Two databases built from the same packages are
+    /// then byte-identical. Note that this keeps all packages in memory until the database is
+    /// finished.
+    ///
+    /// This must be called before any package is added.
+    pub fn set_package_order(&mut self, order: PackageOrder) {
+        self.order = order;
+    }
+
+    /// Sets the creation time (in seconds since the unix epoch) that is recorded in the metadata,
+    /// instead of the time at which the database is finished.
+    pub fn set_created(&mut self, created: u64) {
+        self.created = Some(created);
+    }
+
     /// Add a new package to the database for the given store path with its corresponding
     /// file tree. Entries are only added if they match `filter_prefix`.
     pub fn add(
@@ -69,38 +395,315 @@ impl Writer {
         files: FileTree,
         filter_prefix: &[u8],
     ) -> io::Result<()> {
-        let entries = files.to_list(filter_prefix);
+        self.add_entries(path, files.to_list(filter_prefix))
+    }
 
+    /// Add a new package to the database for the given store path with the given file entries.
+    ///
+    /// In contrast to `add`, the entries are added as they are, without any filtering.
+    pub fn add_entries(&mut self, path: StorePath, entries: Vec<FileTreeEntry>) -> io::Result<()> {
         // Don't add packages with no file entries to the database.
         if entries.is_empty() {
             return Ok(());
         }
-        let writer = self.writer.as_mut().expect("not dropped yet");
+        if self.order != PackageOrder::Added {
+            self.buffered.push((path, entries));
+            return Ok(());
+        }
+        self.write_package(path, entries)
+    }
+
+    /// Writes the buffered packages in the configured order.
+    fn write_buffered(&mut self) -> io::Result<()> {
+        let mut buffered = mem::take(&mut self.buffered);
+        // Sorting by the complete store path and origin makes the order total, so it does not
+        // depend on the order in which the packages were added, as long as there are no
+        // duplicates.
+        match self.order {
+            PackageOrder::Added => {}
+            PackageOrder::Hash => buffered.sort_by(|(a, _), (b, _)| {
+                (a.hash(), a.as_str(), a.origin()).cmp(&(b.hash(), b.as_str(), b.origin()))
+            }),
+            PackageOrder::Attr => buffered
+                .sort_by(|(a, _), (b, _)| (a.origin(), a.as_str()).cmp(&(b.origin(), b.as_str()))),
+        }
+        for (path, entries) in buffered {
+            self.write_package(path, entries)?;
+        }
+        Ok(())
+    }
+
+    /// Adds the entries of a package to the current frame.
+    fn write_package(&mut self, path: StorePath, entries: Vec<FileTreeEntry>) -> io::Result<()> {
+        self.packages += 1;
+        self.entries += entries.len() as u64;
+        if let Some(system) = &path.origin().system {
+            self.systems.insert(system.clone());
+        }
+
+        // the entries are added to the frame that is currently being filled
+        let frame = (self.footer.frames.len() + self.pending.len()) as u32;
+        for entry in &entries {
+            for trigram in entry.path.windows(3) {
+                let bit = trigram_bit(trigram, TRIGRAM_FILTER_BITS);
+                self.trigram_filter[(bit / 8) as usize] |= 1 << (bit % 8);
+            }
+            if indexed_directory(&self.indexed_directories, &entry.path).is_some() {
+                self.basenames
+                    .entry(entry.path.clone())
+                    .or_default()
+                    .insert(frame);
+            }
+        }
+
+        let start = self.frame.len();
         let mut encoder = frcode::Encoder::new(
-            writer,
+            &mut self.frame,
             b"p".to_vec(),
             serde_json::to_vec(&path).expect("failed to serialize path"),
         );
         for entry in entries {
             entry.encode(&mut encoder)?;
         }
+        encoder.finish()?;
+        if self.dictionary_size > 0 && self.footer.dictionary.is_none() {
+            self.samples.push(self.frame.len() - start);
+        }
+
+        // Since the package entry comes after the file entries of a package, the frame now
+        // ends at a package boundary, so this is a point where we may start a new frame.
+        if self.frame.len() >= self.frame_size {
+            self.close_frame();
+            if self.pending.len() >= self.parallel_frames && !self.needs_training_data() {
+                self.write_pending()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns whether the dictionary still needs to be trained and more frames should be
+    /// buffered for it first.
+    ///
+    /// The dictionary is always trained on the same amount of data (if there is enough), so it
+    /// does not depend on how many frames are compressed in parallel.
+    fn needs_training_data(&self) -> bool {
+        self.dictionary_size > 0
+            && self.footer.dictionary.is_none()
+            && self.samples.iter().sum::<usize>() < self.max_training_size()
+    }
+
+    /// Returns the maximum number of bytes on which the dictionary is trained.
+    fn max_training_size(&self) -> usize {
+        // zstd recommends about 100 times the size of the dictionary as training data
+        self.dictionary_size.saturating_mul(100)
+    }
+
+    /// Closes the frame that is currently being filled, so that it will be written with the
+    /// next call to `write_pending`.
+    fn close_frame(&mut self) {
+        self.pending.push(mem::take(&mut self.frame));
+        self.trigram_filters.extend_from_slice(&self.trigram_filter);
+        self.trigram_filter.fill(0);
+    }
+
+    /// Writes the trigram filters of all frames to the file, returning their location.
+    fn write_trigram_filters(&mut self) -> io::Result<TrigramFilters> {
+        let filters = TrigramFilters {
+            offset: self.offset,
+            bits: TRIGRAM_FILTER_BITS,
+            checksum: crc32fast::hash(&self.trigram_filters),
+        };
+        let file = self.file.as_mut().expect("not finished yet");
+        file.write_all(&self.trigram_filters)?;
+        self.offset += self.trigram_filters.len() as u64;
+        self.trigram_filters = Vec::new();
+        Ok(filters)
+    }
+
+    /// Trains the zstd dictionary on the pending frames and writes it to the file.
+    ///
+    /// If there is not enough data to train a dictionary, no dictionary is used.
+    fn write_dictionary(&mut self) -> io::Result<()> {
+        let max_training_size = self.max_training_size();
+        let mut samples = mem::take(&mut self.samples);
+        let mut training_size = 0;
+        let count = samples
+            .iter()
+            .take_while(|&&size| {
+                training_size += size;
+                training_size <= max_training_size
+            })
+            .count();
+        samples.truncate(count);
+        let data = self.pending.concat();
+        let data = &data[..samples.iter().sum::<usize>()];
+
+        let dictionary = match zstd::dict::from_continuous(data, &samples, self.dictionary_size) {
+            Ok(dictionary) => dictionary,
+            Err(_) => {
+                self.dictionary_size = 0;
+                return Ok(());
+            }
+        };
+
+        let file = self.file.as_mut().expect("not finished yet");
+        file.write_all(&dictionary)?;
+        self.footer.dictionary = Some(Dictionary {
+            offset: self.offset,
+            len: dictionary.len() as u64,
+            checksum: crc32fast::hash(&dictionary),
+        });
+        self.offset += dictionary.len() as u64;
+        self.dictionary = Some(dictionary);
+        Ok(())
+    }
+
+    /// Compresses all pending frames in parallel and appends them to the file.
+    fn write_pending(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        if self.dictionary_size > 0 && self.footer.dictionary.is_none() {
+            self.write_dictionary()?;
+        }
+
+        let level = self.level;
+        let long_distance_matching = self.long_distance_matching;
+        let dictionary = self
+            .dictionary
+            .as_ref()
+            .map(|dictionary| EncoderDictionary::copy(dictionary, level));
+        // If there are fewer frames than CPUs, use the remaining CPUs as zstd worker threads.
+        // We always use at least one worker, since the compressed data is the same for any
+        // number of workers, but differs from the data produced without workers.
+        let workers = (num_cpus::get() / self.pending.len()).max(1) as u32;
+        let compressed = self
+            .pending
+            .par_iter()
+            .map(|frame| {
+                let mut compressor = match dictionary {
+                    Some(ref dictionary) => Compressor::with_prepared_dictionary(dictionary)?,
+                    None => Compressor::new(level)?,
+                };
+                compressor.set_parameter(CParameter::NbWorkers(workers))?;
+                if long_distance_matching {
+                    compressor.set_parameter(CParameter::EnableLongDistanceMatching(true))?;
+                }
+                compressor.compress(frame)
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        self.pending.clear();
+
+        let file = self.file.as_mut().expect("not finished yet");
+        for data in compressed {
+            file.write_all(&data)?;
+            let len = data.len() as u64;
+            self.footer.frames.push(Frame {
+                offset: self.offset,
+                len,
+                checksum: crc32fast::hash(&data),
+            });
+            self.offset += len;
+        }
         Ok(())
     }
 
-    /// Finishes encoding. After calling this function, `add` may no longer be called, since this function
-    /// closes the stream.
+    /// Writes the basename index to the file, returning its location.
+    fn write_basename_index(&mut self) -> io::Result<BasenameIndex> {
+        let basenames = mem::take(&mut self.basenames);
+        let buckets = (basenames.len() as u64 / 16).max(1);
+        let mut index = BasenameIndex {
+            directories: self.indexed_directories.clone(),
+            offset: self.offset,
+            len: 0,
+            buckets,
+            checksum: 0,
+        };
+
+        let mut bucket_contents = vec![Vec::new(); buckets as usize];
+        for (path, frames) in basenames {
+            let bucket = index.bucket(&path) as usize;
+            bucket_contents[bucket].push((path, frames.into_iter().collect::<Vec<_>>()));
+        }
+
+        let encoded = bucket_contents
+            .into_iter()
+            .map(|mut bucket| {
+                bucket.sort();
+                bincode::serde::encode_to_vec(&bucket, bincode::config::standard())
+                    .map_err(io::Error::other)
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let mut data = Vec::new();
+        let mut bucket_offset = (buckets + 1) * 8;
+        for bucket in &encoded {
+            data.write_u64::<LittleEndian>(bucket_offset)?;
+            bucket_offset += bucket.len() as u64;
+        }
+        data.write_u64::<LittleEndian>(bucket_offset)?;
+        for bucket in encoded {
+            data.extend_from_slice(&bucket);
+        }
+
+        index.len = data.len() as u64;
+        index.checksum = crc32fast::hash(&data);
+        let file = self.file.as_mut().expect("not finished yet");
+        file.write_all(&data)?;
+        self.offset += index.len;
+        Ok(index)
+    }
+
+    /// Finishes the database by writing all remaining frames and the footer. After calling this
+    /// function, `add` may no longer be called.
     ///
     /// The return value is the underlying File.
-    fn finish_encoder(&mut self) -> io::Result<File> {
-        let writer = self.writer.take().expect("not dropped yet");
-        let encoder = writer.into_inner()?;
-        encoder.finish()
+    fn finish_file(&mut self) -> io::Result<File> {
+        self.write_buffered()?;
+        if !self.frame.is_empty() {
+            self.close_frame();
+        }
+        self.write_pending()?;
+        self.footer.trigram_filters = Some(self.write_trigram_filters()?);
+        if !self.indexed_directories.is_empty() {
+            self.footer.basename_index = Some(self.write_basename_index()?);
+        }
+
+        let metadata = &mut self.footer.metadata;
+        metadata.generator = format!("nix-index {}", env!("CARGO_PKG_VERSION"));
+        metadata.created = self.created.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs())
+        });
+        metadata.systems = mem::take(&mut self.systems).into_iter().collect();
+        metadata.packages = self.packages;
+        metadata.entries = self.entries;
+
+        let mut file = self.file.take().expect("not finished yet");
+        let footer = bincode::serde::encode_to_vec(&self.footer, bincode::config::standard())
+            .map_err(io::Error::other)?;
+        file.write_all(&footer)?;
+        file.write_u64::<LittleEndian>(footer.len() as u64)?;
+        file.write_u32::<LittleEndian>(crc32fast::hash(&footer))?;
+        file.write_all(FILE_MAGIC)?;
+        file.into_inner().map_err(|e| e.into_error())
     }
 
     /// Finish the encoding and return the size in bytes of the compressed file that was created.
+    ///
+    /// The finished database atomically replaces any existing file at the path of the database.
     pub fn finish(mut self) -> io::Result<u64> {
-        let mut file = self.finish_encoder()?;
-        file.stream_position()
+        let result = self.finish_file().and_then(|mut file| {
+            file.sync_all()?;
+            let size = file.stream_position()?;
+            fs::rename(&self.temp_path, &self.path)?;
+            Ok(size)
+        });
+        if result.is_err() {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+        result
     }
 }
 
@@ -108,8 +711,32 @@ impl Writer {
 pub enum Error {
     #[error("expected file to start with nix-index file magic 'NIXI', but found '{found:?}' (is this a valid nix-index database file?)")]
     UnsupportedFileType { found: Vec<u8> },
-    #[error("this executable only supports the nix-index database version {}, but found a database with version {found}", FORMAT_VERSION)]
+    #[error("this executable only supports the nix-index database versions {} to {}, but found a database with version {found}", MIN_FORMAT_VERSION, FORMAT_VERSION)]
     UnsupportedVersion { found: u64 },
+    #[error("database corrupt, the file does not end with the nix-index file magic (is the file truncated?)")]
+    MissingTrailer,
+    #[error("database corrupt, the checksum of the footer does not match")]
+    FooterChecksumMismatch,
+    #[error("database corrupt, the checksum of the frame at offset {offset} does not match")]
+    FrameChecksumMismatch { offset: u64 },
+    #[error("database corrupt, the metadata records {expected} {what}, but the database contains {found}")]
+    CountMismatch {
+        what: &'static str,
+        expected: u64,
+        found: u64,
+    },
+    #[error("database corrupt, the checksum of the compression dictionary does not match")]
+    DictionaryChecksumMismatch,
+    #[error("database corrupt, the checksum of the trigram filters does not match")]
+    TrigramFiltersChecksumMismatch,
+    #[error("database corrupt, could not decode the basename index")]
+    BasenameIndexCorrupt,
+    #[error("database corrupt, could not decode footer: {0}")]
+    FooterDecode(#[from] bincode::error::DecodeError),
+    #[error(
+        "database corrupt, frame at offset {offset} with length {len} exceeds the end of the file"
+    )]
+    FrameOutOfRange { offset: u64, len: u64 },
     #[error("database corrupt, found a file entry without a matching package entry")]
     MissingPackageEntry,
     #[error("database corrupt, frcode error: {0}")]
@@ -126,9 +753,55 @@ pub enum Error {
 
 type Result<T> = std::result::Result<T, Error>;
 
+/// A reader for a section of the database file.
+///
+/// Sections are read with positioned reads, so multiple sections of the same file can be
+/// read concurrently.
+struct Section {
+    file: Arc<File>,
+    /// The offset of the next byte to read.
+    offset: u64,
+    /// The offset of the first byte after the section.
+    end: u64,
+}
+
+impl Read for Section {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = (self.end - self.offset).min(buf.len() as u64) as usize;
+        let n = self.file.read_at(&mut buf[..remaining], self.offset)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+/// A decoder for the entries stored in a single frame.
+type FrameDecoder = frcode::Decoder<BufReader<zstd::Decoder<'static, BufReader<Section>>>>;
+
 /// A Reader allows fast querying of a nix-index database.
+///
+/// Cloning a reader is cheap, since the file and the decoded frames are shared.
+#[derive(Clone)]
 pub struct Reader {
-    decoder: frcode::Decoder<BufReader<zstd::Decoder<'static, BufReader<File>>>>,
+    /// The database file.
+    file: Arc<File>,
+    /// The version of the database format.
+    version: u64,
+    /// Information about how the database was built, if the database stores it.
+    metadata: Option<Metadata>,
+    /// The frames of the database, in order.
+    ///
+    /// A database with format version 1 consists of a single zstd stream, which we treat
+    /// as a single frame.
+    frames: Vec<Frame>,
+    /// The basename index, if the database has one.
+    basename_index: Option<BasenameIndex>,
+    /// The trigram filters of the frames, if the database has them.
+    trigram_filters: Option<TrigramFilters>,
+    /// The zstd dictionary with which the frames were compressed, or an empty slice if the
+    /// frames were compressed without a dictionary.
+    dictionary: Arc<[u8]>,
+    /// The cache of the decompressed frames, if queries should search the cache.
+    cache: Option<Arc<Cache>>,
 }
 
 impl Reader {
@@ -147,16 +820,412 @@ impl Reader {
         }
 
         let version = file.read_u64::<LittleEndian>()?;
-        if version != FORMAT_VERSION {
+        if !(MIN_FORMAT_VERSION..=FORMAT_VERSION).contains(&version) {
             return Err(Error::UnsupportedVersion { found: version });
         }
 
-        let decoder = zstd::Decoder::new(file)?;
+        let file_len = file.metadata()?.len();
+        let (mut footer, metadata) = if version == 1 {
+            let frame = Frame {
+                offset: HEADER_LEN,
+                len: file_len - HEADER_LEN,
+                checksum: 0,
+            };
+            let footer = Footer {
+                frames: vec![frame],
+                ..Footer::default()
+            };
+            (footer, None)
+        } else {
+            let mut footer = Self::read_footer(&mut file, file_len)?;
+            for frame in &footer.frames {
+                if frame.offset.saturating_add(frame.len) > file_len {
+                    return Err(Error::FrameOutOfRange {
+                        offset: frame.offset,
+                        len: frame.len,
+                    });
+                }
+            }
+            let metadata = mem::take(&mut footer.metadata);
+            (footer, Some(metadata))
+        };
+
+        let dictionary = match footer.dictionary.take() {
+            Some(dictionary) => {
+                let mut data = vec![0; dictionary.len as usize];
+                file.read_exact_at(&mut data, dictionary.offset)?;
+                if crc32fast::hash(&data) != dictionary.checksum {
+                    return Err(Error::DictionaryChecksumMismatch);
+                }
+                data
+            }
+            None => Vec::new(),
+        };
+
         Ok(Reader {
-            decoder: frcode::Decoder::new(BufReader::new(decoder)),
+            file: Arc::new(file),
+            version,
+            frames: footer.frames,
+            metadata,
+            basename_index: footer.basename_index,
+            trigram_filters: footer.trigram_filters,
+            dictionary: dictionary.into(),
+            cache: None,
         })
     }
 
+    /// Returns the version of the format of this database.
+    pub fn format_version(&self) -> u64 {
+        self.version
+    }
+
+    /// Returns the information about how this database was built.
+    ///
+    /// Databases in format version 1 do not store any metadata, so this returns `None` for them.
+    pub fn metadata(&self) -> Option<&Metadata> {
+        self.metadata.as_ref()
+    }
+
+    /// Looks up the frames that contain the given path in the basename index.
+    ///
+    /// Returns `None` if the path is not covered by the basename index, either because the
+    /// database has no index or because the path is not a direct child of an indexed directory.
+    /// Otherwise, only the frames with the returned indices can contain an entry for the path.
+    fn basename_frames(&self, path: &[u8]) -> Result<Option<Vec<usize>>> {
+        let index = match self.basename_index {
+            Some(ref index) if index.covers(path) => index,
+            _ => return Ok(None),
+        };
+
+        let mut range = [0; 16];
+        self.file
+            .read_exact_at(&mut range, index.offset + index.bucket(path) * 8)?;
+        let start = u64::from_le_bytes(range[..8].try_into().expect("slice has 8 bytes"));
+        let end = u64::from_le_bytes(range[8..].try_into().expect("slice has 8 bytes"));
+        if start > end || end > index.len {
+            return Err(Error::BasenameIndexCorrupt);
+        }
+
+        let mut bucket = vec![0; (end - start) as usize];
+        self.file.read_exact_at(&mut bucket, index.offset + start)?;
+        let (bucket, _): (Vec<(Vec<u8>, Vec<u32>)>, _) =
+            bincode::serde::decode_from_slice(&bucket, bincode::config::standard())
+                .map_err(|_| Error::BasenameIndexCorrupt)?;
+
+        let frames = match bucket.binary_search_by(|(p, _)| p.as_slice().cmp(path)) {
+            Ok(i) => &bucket[i].1,
+            Err(_) => return Ok(Some(Vec::new())),
+        };
+        frames
+            .iter()
+            .map(|&i| {
+                let i = i as usize;
+                if i < self.frames.len() {
+                    Ok(i)
+                } else {
+                    Err(Error::BasenameIndexCorrupt)
+                }
+            })
+            .collect::<Result<Vec<_>>>()
+            .map(Some)
+    }
+
+    /// Returns the indices of the frames that may contain an entry for the given path,
+    /// according to the basename index and the trigram filters.
+    fn path_frames(&self, path: &[u8]) -> Result<Vec<usize>> {
+        let mut frames = self
+            .basename_frames(path)?
+            .unwrap_or_else(|| (0..self.frames.len()).collect());
+        self.filter_frames(&mut frames, &[path.to_vec()])?;
+        Ok(frames)
+    }
+
+    /// Removes the indices of all frames that cannot contain a path with each of the given
+    /// literals from `frames`, according to the trigram filters.
+    ///
+    /// If the database has no trigram filters, `frames` is left unchanged.
+    fn filter_frames(&self, frames: &mut Vec<usize>, literals: &[Vec<u8>]) -> Result<()> {
+        let Some(ref filters) = self.trigram_filters else {
+            return Ok(());
+        };
+        let bits = literals
+            .iter()
+            .flat_map(|literal| literal.windows(3))
+            .map(|trigram| trigram_bit(trigram, filters.bits))
+            .collect::<BTreeSet<_>>();
+        if bits.is_empty() {
+            return Ok(());
+        }
+
+        let filter_len = filters.bits / 8;
+        let mut data = vec![0; (filter_len * self.frames.len() as u64) as usize];
+        self.file.read_exact_at(&mut data, filters.offset)?;
+        frames.retain(|&frame| {
+            let filter = &data[frame * filter_len as usize..][..filter_len as usize];
+            bits.iter()
+                .all(|&bit| filter[(bit / 8) as usize] & (1 << (bit % 8)) != 0)
+        });
+        Ok(())
+    }
+
+    /// Reads the footer from the end of the file.
+    fn read_footer(file: &mut File, file_len: u64) -> Result<Footer> {
+        if file_len < HEADER_LEN + TRAILER_LEN {
+            return Err(Error::MissingTrailer);
+        }
+        file.seek(SeekFrom::Start(file_len - TRAILER_LEN))?;
+        let footer_len = file.read_u64::<LittleEndian>()?;
+        let checksum = file.read_u32::<LittleEndian>()?;
+        let mut magic = [0u8; 4];
+        file.read_exact(&mut magic)?;
+        if magic != FILE_MAGIC || footer_len > file_len - HEADER_LEN - TRAILER_LEN {
+            return Err(Error::MissingTrailer);
+        }
+
+        let mut footer = vec![0; footer_len as usize];
+        file.seek(SeekFrom::Start(file_len - TRAILER_LEN - footer_len))?;
+        file.read_exact(&mut footer)?;
+        if crc32fast::hash(&footer) != checksum {
+            return Err(Error::FooterChecksumMismatch);
+        }
+        let (footer, _) = bincode::serde::decode_from_slice(&footer, bincode::config::standard())?;
+        Ok(footer)
+    }
+
+    /// Returns a decoder for the entries of the given frame.
+    fn frame_decoder(&self, frame: &Frame) -> Result<FrameDecoder> {
+        frame_decoder(&self.file, &self.dictionary, frame)
+    }
+
+    /// Makes queries search a memory-mapped cache of the decompressed frames at the given path,
+    /// instead of decompressing the frames for every query.
+    ///
+    /// If there is no cache at that path yet, or the cache was created for a different database
+    /// (for example, before the database was rebuilt), the cache is created first, which
+    /// requires decompressing the whole database once. See the `cache` module for details.
+    pub fn use_cache<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let key = cache::Key::new(
+            &self.file.metadata()?,
+            self.frames.iter().map(|frame| frame.checksum).collect(),
+        );
+        let cache = match Cache::open(path, &key)? {
+            Some(cache) if cache.len() == self.frames.len() => cache,
+            _ => {
+                let mut writer = cache::Writer::create(path)?;
+                self.decode_frames(|data| Ok(writer.add_frame(data)?))?;
+                writer.finish(key)?
+            }
+        };
+        self.cache = Some(Arc::new(cache));
+        Ok(())
+    }
+
+    /// Decompresses all frames into memory, so that queries search the decoded entries directly.
+    ///
+    /// This is useful for long-running processes that answer many queries. The decoded entries
+    /// take several times the size of the database.
+    pub fn load_into_memory(&mut self) -> Result<()> {
+        let mut data = Vec::new();
+        let mut frames = Vec::with_capacity(self.frames.len());
+        self.decode_frames(|frame| {
+            frames.push(data.len()..data.len() + frame.len());
+            data.extend_from_slice(frame);
+            Ok(())
+        })?;
+        self.cache = Some(Arc::new(Cache::in_memory(data, frames)));
+        Ok(())
+    }
+
+    /// Decodes all frames and passes the decoded entries of each frame to `add`, in order.
+    fn decode_frames(&self, mut add: impl FnMut(&[u8]) -> Result<()>) -> Result<()> {
+        // decode the frames in parallel, but pass them on in order
+        for batch in self.frames.chunks(rayon::current_num_threads()) {
+            let decoded = batch
+                .par_iter()
+                .map(|frame| {
+                    let mut decoder = self.frame_decoder(frame)?;
+                    let mut data = Vec::new();
+                    loop {
+                        let block = decoder.decode()?;
+                        if block.is_empty() {
+                            return Ok(data);
+                        }
+                        data.extend_from_slice(block);
+                    }
+                })
+                .collect::<Result<Vec<_>>>()?;
+            for data in decoded {
+                add(&data)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns an iterator over all packages in the database together with their file entries,
+    /// in the order in which they are stored in the database.
+    pub fn packages(&self) -> Packages {
+        Packages {
+            file: self.file.clone(),
+            dictionary: self.dictionary.clone(),
+            frames: self.frames.clone(),
+            next_frame: 0,
+            decoder: None,
+            found: VecDeque::new(),
+            entries: Vec::new(),
+        }
+    }
+
+    /// Returns an iterator over the packages of a single frame.
+    fn frame_packages(&self, frame: &Frame) -> Packages {
+        Packages {
+            file: self.file.clone(),
+            dictionary: self.dictionary.clone(),
+            frames: vec![*frame],
+            next_frame: 0,
+            decoder: None,
+            found: VecDeque::new(),
+            entries: Vec::new(),
+        }
+    }
+
+    /// Returns the file entries of all packages for which `filter` returns true, in the order in
+    /// which they are stored in the database.
+    ///
+    /// Unlike `packages`, this only decodes the file entries of the packages that are returned,
+    /// so it is much faster for finding a few packages.
+    pub fn package_entries<F>(&self, filter: F) -> Result<Vec<(StorePath, Vec<FileTreeEntry>)>>
+    where
+        F: Fn(&StorePath) -> bool + Sync,
+    {
+        let frames = (0..self.frames.len()).collect::<Vec<_>>();
+        self.frame_package_entries(&frames, filter)
+    }
+
+    /// Like `package_entries`, but only searches the frames with the given indices.
+    fn frame_package_entries<F>(
+        &self,
+        frames: &[usize],
+        filter: F,
+    ) -> Result<Vec<(StorePath, Vec<FileTreeEntry>)>>
+    where
+        F: Fn(&StorePath) -> bool + Sync,
+    {
+        let found = frames
+            .par_iter()
+            .map(|&frame| {
+                let frame = &self.frames[frame];
+                let mut decoder = self.frame_decoder(frame)?;
+                let mut found = Vec::new();
+                // the raw entries after the last package entry, which belong to the next package
+                let mut entries = Vec::new();
+                loop {
+                    let block = decoder.decode()?;
+                    if block.is_empty() {
+                        break;
+                    }
+                    for line in block
+                        .strip_suffix(b"\n")
+                        .unwrap_or(block)
+                        .split(|c| *c == b'\n')
+                    {
+                        let Some(json) = line.strip_prefix(b"p\0") else {
+                            entries.extend_from_slice(line);
+                            entries.push(b'\n');
+                            continue;
+                        };
+                        let path = decode_package(json)?;
+                        if filter(&path) {
+                            let entries = entries
+                                .split(|c| *c == b'\n')
+                                .filter(|line| !line.is_empty())
+                                .map(|line| {
+                                    FileTreeEntry::decode(line).ok_or_else(|| Error::EntryParse {
+                                        entry: line.to_vec(),
+                                    })
+                                })
+                                .collect::<Result<_>>()?;
+                            found.push((path, entries));
+                        }
+                        entries.clear();
+                    }
+                }
+                Ok(found)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(found.into_iter().flatten().collect())
+    }
+
+    /// Checks the integrity of the whole database.
+    ///
+    /// This verifies the checksums of every frame, the basename index and the trigram filters,
+    /// decodes all entries and checks that every
+    /// file entry belongs to a package entry. If the database stores metadata, the number of
+    /// packages and entries found is compared to the counts recorded there.
+    ///
+    /// Databases in format version 1 do not store checksums or counts, so for them, only the
+    /// entries are checked.
+    pub fn verify(&self) -> Result<Verification> {
+        let counts = self
+            .frames
+            .par_iter()
+            .map(|frame| {
+                if self.version > 1 {
+                    let mut data = vec![0; frame.len as usize];
+                    self.file.read_exact_at(&mut data, frame.offset)?;
+                    if crc32fast::hash(&data) != frame.checksum {
+                        return Err(Error::FrameChecksumMismatch {
+                            offset: frame.offset,
+                        });
+                    }
+                }
+
+                self.frame_packages(frame)
+                    .try_fold((0, 0), |(packages, entries), package| {
+                        let (_, package_entries) = package?;
+                        Ok((packages + 1, entries + package_entries.len() as u64))
+                    })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        if let Some(index) = &self.basename_index {
+            let mut data = vec![0; index.len as usize];
+            self.file.read_exact_at(&mut data, index.offset)?;
+            if crc32fast::hash(&data) != index.checksum {
+                return Err(Error::BasenameIndexCorrupt);
+            }
+        }
+        if let Some(filters) = &self.trigram_filters {
+            let mut data = vec![0; (filters.bits / 8 * self.frames.len() as u64) as usize];
+            self.file.read_exact_at(&mut data, filters.offset)?;
+            if crc32fast::hash(&data) != filters.checksum {
+                return Err(Error::TrigramFiltersChecksumMismatch);
+            }
+        }
+
+        let verification = Verification {
+            frames: self.frames.len() as u64,
+            packages: counts.iter().map(|c| c.0).sum(),
+            entries: counts.iter().map(|c| c.1).sum(),
+        };
+
+        if let Some(metadata) = &self.metadata {
+            for (what, expected, found) in [
+                ("packages", metadata.packages, verification.packages),
+                ("entries", metadata.entries, verification.entries),
+            ] {
+                if expected != found {
+                    return Err(Error::CountMismatch {
+                        what,
+                        expected,
+                        found,
+                    });
+                }
+            }
+        }
+        Ok(verification)
+    }
+
     /// Builds a query to find all entries in the database that have a filename matching the given pattern.
     ///
     /// Afterwards, use `Query::into_iter` to iterate over the items.
@@ -166,26 +1235,379 @@ impl Reader {
             exact_regex,
             hash: None,
             package_pattern: None,
+            attr_pattern: None,
+            output_pattern: None,
+            system: None,
+            min_size: None,
+            max_size: None,
+            target_pattern: None,
+        }
+    }
+
+    /// Dumps the contents of the database to stdout, for debugging.
+    #[allow(clippy::print_stdout)]
+    pub fn dump(&mut self) -> Result<()> {
+        for frame in &self.frames {
+            let mut decoder = self.frame_decoder(frame)?;
+            loop {
+                let block = decoder.decode()?;
+                if block.is_empty() {
+                    break;
+                }
+                for line in block.split(|c| *c == b'\n') {
+                    println!("{:?}", String::from_utf8_lossy(line));
+                }
+                println!("-- block boundary");
+            }
+            println!("-- frame boundary");
+        }
+        Ok(())
+    }
+}
+
+/// The result of a successful `Reader::verify`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Verification {
+    /// The number of frames in the database.
+    pub frames: u64,
+    /// The number of packages in the database.
+    pub packages: u64,
+    /// The number of file entries in the database.
+    pub entries: u64,
+}
+
+/// Returns a decoder for the entries of the given frame of the database `file`, which was
+/// compressed with the given zstd `dictionary` (empty if no dictionary was used).
+fn frame_decoder(file: &Arc<File>, dictionary: &[u8], frame: &Frame) -> Result<FrameDecoder> {
+    let section = Section {
+        file: file.clone(),
+        offset: frame.offset,
+        end: frame.offset + frame.len,
+    };
+    let decoder = zstd::Decoder::with_dictionary(BufReader::new(section), dictionary)?;
+    Ok(frcode::Decoder::new(BufReader::new(decoder)))
+}
+
+/// Parses the JSON-encoded store path of a package entry.
+fn decode_package(json: &[u8]) -> Result<StorePath> {
+    serde_json::from_slice(json).map_err(|_| Error::StorePathParse {
+        path: json.to_vec(),
+    })
+}
+
+/// An iterator over all packages of a database, created by `Reader::packages`.
+pub struct Packages {
+    /// The database file.
+    file: Arc<File>,
+    /// The zstd dictionary of the database.
+    dictionary: Arc<[u8]>,
+    /// The frames of the database.
+    frames: Vec<Frame>,
+    /// The index of the next frame to decode.
+    next_frame: usize,
+    /// The decoder for the frame that is currently being decoded.
+    decoder: Option<FrameDecoder>,
+    /// Packages that were decoded but have not been returned by `next` yet.
+    found: VecDeque<(StorePath, Vec<FileTreeEntry>)>,
+    /// File entries for which the package entry has not been decoded yet.
+    entries: Vec<FileTreeEntry>,
+}
+
+impl Packages {
+    /// Decodes the next block of entries, until `self.found` contains at least one package
+    /// or the end of the database has been reached.
+    fn fill_buf(&mut self) -> Result<()> {
+        while self.found.is_empty() {
+            let decoder = match self.decoder {
+                Some(ref mut decoder) => decoder,
+                None if self.next_frame < self.frames.len() => {
+                    let frame = &self.frames[self.next_frame];
+                    self.next_frame += 1;
+                    self.decoder
+                        .insert(frame_decoder(&self.file, &self.dictionary, frame)?)
+                }
+                None => return Ok(()),
+            };
+
+            let block = match decoder.decode() {
+                Ok(block) => block,
+                Err(e) => {
+                    // the rest of the frame cannot be decoded anymore
+                    self.decoder = None;
+                    return Err(e.into());
+                }
+            };
+            if block.is_empty() {
+                self.decoder = None;
+                // frames always end with a package entry
+                if !self.entries.is_empty() {
+                    self.entries.clear();
+                    return Err(Error::MissingPackageEntry);
+                }
+                continue;
+            }
+
+            for line in block
+                .strip_suffix(b"\n")
+                .unwrap_or(block)
+                .split(|c| *c == b'\n')
+            {
+                if let Some(json) = line.strip_prefix(b"p\0") {
+                    let entries = mem::take(&mut self.entries);
+                    self.found.push_back((decode_package(json)?, entries));
+                } else {
+                    let entry = FileTreeEntry::decode(line).ok_or_else(|| Error::EntryParse {
+                        entry: line.to_vec(),
+                    })?;
+                    self.entries.push(entry);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for Packages {
+    type Item = Result<(StorePath, Vec<FileTreeEntry>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.fill_buf() {
+            Err(e) => Some(Err(e)),
+            Ok(()) => self.found.pop_front().map(Ok),
+        }
+    }
+}
+
+/// Merges several databases into one, writing the result to `writer`.
+///
+/// Packages that are contained in multiple databases (identified by the hash of their store path)
+/// are only added once. Top-level paths take precedence over paths that were only found as a
+/// dependency, otherwise the package from the database that comes first in `inputs` is used.
+///
+/// Returns the number of packages that were added to the merged database.
+pub fn merge(inputs: &[Reader], writer: &mut Writer) -> Result<u64> {
+    // First, decide which database each package will be taken from.
+    let mut sources: HashMap<String, (usize, bool)> = HashMap::new();
+    for (index, input) in inputs.iter().enumerate() {
+        for package in input.packages() {
+            let (path, _) = package?;
+            let toplevel = path.origin().toplevel;
+            match sources.entry(path.hash().into_owned()) {
+                hash_map::Entry::Occupied(mut e) => {
+                    if toplevel && !e.get().1 {
+                        e.insert((index, toplevel));
+                    }
+                }
+                hash_map::Entry::Vacant(e) => {
+                    e.insert((index, toplevel));
+                }
+            }
+        }
+    }
+
+    writer.set_metadata(merge_metadata(inputs));
+
+    let mut added = 0;
+    for (index, input) in inputs.iter().enumerate() {
+        for package in input.packages() {
+            let (path, entries) = package?;
+            if sources.get(path.hash().as_ref()).map(|s| s.0) == Some(index) {
+                sources.remove(path.hash().as_ref());
+                writer.add_entries(path, entries)?;
+                added += 1;
+            }
+        }
+    }
+    Ok(added)
+}
+
+/// Combines the metadata of several databases into the metadata for the merged database.
+///
+/// Information that differs between the databases (such as the version of nixpkgs) is dropped.
+fn merge_metadata(inputs: &[Reader]) -> Metadata {
+    // Databases without metadata have an unknown filter prefix, so treat that as empty.
+    let all = inputs
+        .iter()
+        .map(|input| input.metadata().cloned().unwrap_or_default())
+        .collect::<Vec<_>>();
+
+    let common = |get: fn(&Metadata) -> &Option<String>| -> Option<String> {
+        let first = get(all.first()?);
+        all.iter().all(|m| get(m) == first).then(|| first.clone())?
+    };
+
+    let mut extra_scopes: Vec<String> = Vec::new();
+    for scope in all.iter().flat_map(|m| &m.extra_scopes) {
+        if !extra_scopes.contains(scope) {
+            extra_scopes.push(scope.clone());
+        }
+    }
+
+    let mut filter_prefix = all.first().map_or("", |m| &m.filter_prefix[..]);
+    for m in &all {
+        let common_len = filter_prefix
+            .chars()
+            .zip(m.filter_prefix.chars())
+            .take_while(|(a, b)| a == b)
+            .map(|(c, _)| c.len_utf8())
+            .sum();
+        filter_prefix = &filter_prefix[..common_len];
+    }
+
+    Metadata {
+        nixpkgs: common(|m| &m.nixpkgs),
+        nixpkgs_version: common(|m| &m.nixpkgs_version),
+        extra_scopes,
+        filter_prefix: filter_prefix.to_string(),
+        ..Metadata::default()
+    }
+}
+
+/// Resolves paths in store paths whose files are stored in the database, following symlinks
+/// from one store path into another.
+///
+/// The files of each store path are only read from the database once. To find a store path,
+/// only the frames that may contain the path that is resolved in it are decoded (according to
+/// the basename index and the trigram filters). Resolving many paths at once with
+/// `resolve_all` reads the store paths that they lead to together, which is faster than
+/// resolving them one after the other.
+pub struct Resolver {
+    reader: Reader,
+    /// The directory of the nix store, such as `/nix/store`.
+    store_dir: Vec<u8>,
+    /// The file trees of the store paths that were read so far, by hash. Store paths that are
+    /// not in the database map to `None`.
+    trees: HashMap<Vec<u8>, Option<FileTree>>,
+}
+
+impl Resolver {
+    /// Creates a resolver for the store paths in the given store directory.
+    pub fn new(reader: Reader, store_dir: &str) -> Resolver {
+        Resolver {
+            reader,
+            store_dir: store_dir.as_bytes().to_vec(),
+            trees: HashMap::new(),
+        }
+    }
+
+    /// Resolves absolute paths, such as `/nix/store/<hash>-hello/bin/hello`, following all
+    /// symlinks on the way. The results are in the same order as the paths.
+    ///
+    /// Paths that lead outside of the store, or into store paths that are not in the database,
+    /// are resolved as `Resolved::External`.
+    pub fn resolve_all(&mut self, paths: Vec<Vec<u8>>) -> Result<Vec<Resolved>> {
+        let mut results: Vec<_> = paths.into_iter().map(Resolved::External).collect();
+        // each step follows the paths into the next store path
+        for _ in 0..MAX_SYMLINKS {
+            let mut missing = HashMap::<_, Vec<_>>::new();
+            for result in &results {
+                let Resolved::External(path) = result else {
+                    continue;
+                };
+                match self.split(path) {
+                    Some((hash, _, rest)) if !self.trees.contains_key(hash) => {
+                        missing
+                            .entry(hash.to_vec())
+                            .or_default()
+                            .push(rest.to_vec());
+                    }
+                    _ => {}
+                }
+            }
+            self.load(missing)?;
+
+            let mut done = true;
+            for result in &mut results {
+                let Resolved::External(path) = result else {
+                    continue;
+                };
+                let Some((hash, root, rest)) = self.split(path) else {
+                    continue;
+                };
+                if let Some(Some(tree)) = self.trees.get(hash) {
+                    *result = tree.resolve(root, rest);
+                    done = false;
+                }
+            }
+            if done {
+                return Ok(results);
+            }
         }
+
+        // the paths that are still unresolved point from one store path to the next in a loop
+        for result in &mut results {
+            if matches!(result, Resolved::External(path) if self.known(path)) {
+                *result = Resolved::Loop;
+            }
+        }
+        Ok(results)
+    }
+
+    /// Splits an absolute path into the hash of its store path, the store path itself and the
+    /// path inside the store path. Returns `None` if the path is not inside the store.
+    fn split<'p>(&self, path: &'p [u8]) -> Option<(&'p [u8], &'p [u8], &'p [u8])> {
+        let name = path.strip_prefix(&self.store_dir[..])?.strip_prefix(b"/")?;
+        let root_len = path.len() - name.len() + memchr(b'/', name).unwrap_or(name.len());
+        let hash = &name[..memchr(b'-', name)?];
+        Some((hash, &path[..root_len], &path[root_len..]))
     }
 
-    /// Dumps the contents of the database to stdout, for debugging.
-    #[allow(clippy::print_stdout)]
-    pub fn dump(&mut self) -> Result<()> {
-        loop {
-            let block = self.decoder.decode()?;
-            if block.is_empty() {
+    /// Returns whether the path is inside a store path that is in the database.
+    fn known(&self, path: &[u8]) -> bool {
+        self.split(path)
+            .is_some_and(|(hash, ..)| matches!(self.trees.get(hash), Some(Some(_))))
+    }
+
+    /// Reads the file trees of the store paths with the given hashes from the database, where
+    /// each hash maps to the paths that are resolved in that store path.
+    fn load(&mut self, mut missing: HashMap<Vec<u8>, Vec<Vec<u8>>>) -> Result<()> {
+        // Usually, the paths exist as entries of the store paths, so only the frames that
+        // contain them need to be searched. If a path leads through a symlink to a directory,
+        // it has no entry, but its first component always has (if the store path is in the
+        // database at all). So if that's needed, we search all frames that contain that.
+        let mut searched = HashSet::new();
+        for first_only in [false, true] {
+            if missing.is_empty() {
                 break;
             }
-            for line in block.split(|c| *c == b'\n') {
-                println!("{:?}", String::from_utf8_lossy(line));
+            let mut frames = BTreeSet::new();
+            // looking up the store path itself (the empty path) would not rule out any frames
+            for path in missing.values().flatten().filter(|path| !path.is_empty()) {
+                let path = if first_only {
+                    first_component(path)
+                } else {
+                    path
+                };
+                frames.extend(self.reader.path_frames(path)?);
+            }
+            let frames = frames
+                .into_iter()
+                .filter(|&frame| searched.insert(frame))
+                .collect::<Vec<_>>();
+            let found = self.reader.frame_package_entries(&frames, |path| {
+                missing.contains_key(path.hash().as_bytes())
+            })?;
+            for (path, entries) in found {
+                let hash = path.hash().as_bytes().to_vec();
+                missing.remove(&hash);
+                self.trees.insert(hash, Some(FileTree::from_list(entries)));
             }
-            println!("-- block boundary");
         }
+        self.trees
+            .extend(missing.into_keys().map(|hash| (hash, None)));
         Ok(())
     }
 }
 
+/// Returns the first component of an absolute path, such as `/bin` for `/bin/hello`.
+fn first_component(path: &[u8]) -> &[u8] {
+    let end = path
+        .get(1..)
+        .and_then(|rest| memchr(b'/', rest))
+        .map_or(path.len(), |i| i + 1);
+    &path[..end]
+}
+
 /// A builder for a `ReaderIter` to iterate over entries in the database matching a given pattern.
 pub struct Query<'a, 'b> {
     /// The underlying reader from which we read input.
@@ -199,6 +1621,24 @@ pub struct Query<'a, 'b> {
 
     /// Only include packages whose name matches the given pattern.
     package_pattern: Option<&'b Regex>,
+
+    /// Only include packages whose attribute matches the given pattern.
+    attr_pattern: Option<&'b Regex>,
+
+    /// Only include packages whose output matches the given pattern.
+    output_pattern: Option<&'b Regex>,
+
+    /// Only include packages for the given system.
+    system: Option<String>,
+
+    /// Only include regular files that are at least this large.
+    min_size: Option<u64>,
+
+    /// Only include regular files that are at most this large.
+    max_size: Option<u64>,
+
+    /// Only include symlinks whose target matches the given pattern.
+    target_pattern: Option<&'b Regex>,
 }
 
 impl<'a, 'b> Query<'a, 'b> {
@@ -215,13 +1655,74 @@ impl<'a, 'b> Query<'a, 'b> {
         }
     }
 
+    /// Limit results to entries from packages whose attribute (such as `python3Packages.numpy`)
+    /// matches the given regex if `Some`.
+    pub fn attr_pattern(self, attr_pattern: Option<&'b Regex>) -> Query<'a, 'b> {
+        Query {
+            attr_pattern,
+            ..self
+        }
+    }
+
+    /// Limit results to entries from packages whose output (such as `dev`) matches the given
+    /// regex if `Some`.
+    pub fn output_pattern(self, output_pattern: Option<&'b Regex>) -> Query<'a, 'b> {
+        Query {
+            output_pattern,
+            ..self
+        }
+    }
+
+    /// Limit results to entries from packages for the given system (such as `x86_64-linux`)
+    /// if `Some`. Packages for which the database does not record a system are always included.
+    pub fn system(self, system: Option<String>) -> Query<'a, 'b> {
+        Query { system, ..self }
+    }
+
+    /// Limit results to regular files with a size of at least `min_size` bytes if `Some`.
+    ///
+    /// Directories and symlinks have no size in bytes, so they never match if a minimum or
+    /// maximum size is given.
+    pub fn min_size(self, min_size: Option<u64>) -> Query<'a, 'b> {
+        Query { min_size, ..self }
+    }
+
+    /// Limit results to regular files with a size of at most `max_size` bytes if `Some`.
+    ///
+    /// Directories and symlinks have no size in bytes, so they never match if a minimum or
+    /// maximum size is given.
+    pub fn max_size(self, max_size: Option<u64>) -> Query<'a, 'b> {
+        Query { max_size, ..self }
+    }
+
+    /// Limit results to symlinks whose target matches the given regex if `Some`.
+    pub fn target_pattern(self, target_pattern: Option<&'b Regex>) -> Query<'a, 'b> {
+        Query {
+            target_pattern,
+            ..self
+        }
+    }
+
     /// Runs the query, returning an Iterator that will yield all entries matching the conditions.
     ///
-    /// There is no guarantee about the order of the returned matches.
+    /// The matches are returned in the order in which they are stored in the database, so the
+    /// matches of each package are returned together.
     pub fn run(self) -> Result<ReaderIter<'a, 'b>> {
         let mut expr = regex_syntax::ast::parse::Parser::new()
             .parse(self.exact_regex.as_str())
             .expect("regex cannot be invalid");
+
+        // if the pattern only matches a single path, the basename index may tell us which
+        // frames contain that path, so that we don't need to search all frames.
+        let frames = match exact_path(&expr) {
+            Some(path) => self.reader.basename_frames(&path)?,
+            None => None,
+        };
+        let mut frames = frames.unwrap_or_else(|| (0..self.reader.frames.len()).collect());
+        // skip all frames that do not contain the literals that every match must contain
+        self.reader
+            .filter_frames(&mut frames, &required_literals(&expr))?;
+
         // replace the ^ anchor by a NUL byte, since each entry is of the form `METADATA\0PATH`
         // (so the NUL byte marks the start of the path).
         {
@@ -249,29 +1750,141 @@ impl<'a, 'b> Query<'a, 'b> {
         let grep = regex_builder.build(&format!("{}", expr))?;
         Ok(ReaderIter {
             reader: self.reader,
-            found: Vec::new(),
-            found_without_package: Vec::new(),
-            pattern: grep,
-            exact_pattern: self.exact_regex,
-            package_entry_pattern: regex_builder.build("^p\0").expect("valid regex"),
-            package_name_pattern: self.package_pattern,
-            package_hash: self.hash,
+            frames,
+            next_frame: 0,
+            found: VecDeque::new(),
+            matcher: EntryMatcher {
+                pattern: grep,
+                exact_pattern: self.exact_regex,
+                package_entry_pattern: regex_builder.build("^p\0").expect("valid regex"),
+                package_name_pattern: self.package_pattern,
+                package_attr_pattern: self.attr_pattern,
+                package_output_pattern: self.output_pattern,
+                package_hash: self.hash,
+                package_system: self.system,
+                min_size: self.min_size,
+                max_size: self.max_size,
+                target_pattern: self.target_pattern,
+            },
         })
     }
 }
 
+/// Returns the path matched by a pattern of the form `^PATH$`, where `PATH` only consists
+/// of literal characters.
+fn exact_path(expr: &Ast) -> Option<Vec<u8>> {
+    let Ast::Concat(concat) = expr else {
+        return None;
+    };
+    let (first, rest) = concat.asts.split_first()?;
+    let (last, literals) = rest.split_last()?;
+    match (first, last) {
+        (Ast::Assertion(start), Ast::Assertion(end))
+            if matches!(
+                start.kind,
+                AssertionKind::StartLine | AssertionKind::StartText
+            ) && matches!(end.kind, AssertionKind::EndLine | AssertionKind::EndText) => {}
+        _ => return None,
+    }
+
+    let mut path = String::new();
+    for ast in literals {
+        match ast {
+            Ast::Literal(literal) => path.push(literal.c),
+            _ => return None,
+        }
+    }
+    Some(path.into_bytes())
+}
+
+/// Returns literals that every string matching the pattern must contain.
+///
+/// This is conservative: not every literal of the pattern needs to be returned. If the pattern
+/// contains flags (such as `(?i)`), no literals are returned, since the flags may change how
+/// literals are matched.
+fn required_literals(expr: &Ast) -> Vec<Vec<u8>> {
+    fn has_flags(ast: &Ast) -> bool {
+        match ast {
+            Ast::Flags(_) => true,
+            Ast::Group(g) => {
+                matches!(g.kind, GroupKind::NonCapturing(ref flags) if !flags.items.is_empty())
+                    || has_flags(&g.ast)
+            }
+            Ast::Repetition(r) => has_flags(&r.ast),
+            Ast::Concat(c) => c.asts.iter().any(has_flags),
+            Ast::Alternation(a) => a.asts.iter().any(has_flags),
+            _ => false,
+        }
+    }
+
+    fn collect(ast: &Ast, literals: &mut Vec<Vec<u8>>) {
+        match ast {
+            Ast::Literal(literal) => literals.push(literal.c.to_string().into_bytes()),
+            Ast::Group(g) => collect(&g.ast, literals),
+            Ast::Repetition(r) => {
+                let min = match r.op.kind {
+                    RepetitionKind::ZeroOrOne | RepetitionKind::ZeroOrMore => 0,
+                    RepetitionKind::OneOrMore => 1,
+                    RepetitionKind::Range(RepetitionRange::Exactly(n))
+                    | RepetitionKind::Range(RepetitionRange::AtLeast(n))
+                    | RepetitionKind::Range(RepetitionRange::Bounded(n, _)) => n,
+                };
+                if min > 0 {
+                    collect(&r.ast, literals);
+                }
+            }
+            Ast::Concat(c) => {
+                // consecutive literals form a single, longer literal
+                let mut current = String::new();
+                for ast in &c.asts {
+                    match ast {
+                        Ast::Literal(literal) => current.push(literal.c),
+                        _ => {
+                            if !current.is_empty() {
+                                literals.push(mem::take(&mut current).into_bytes());
+                            }
+                            collect(ast, literals);
+                        }
+                    }
+                }
+                if !current.is_empty() {
+                    literals.push(current.into_bytes());
+                }
+            }
+            // none of the alternatives is required
+            _ => {}
+        }
+    }
+
+    let mut literals = Vec::new();
+    if !has_flags(expr) {
+        collect(expr, &mut literals);
+    }
+    literals
+}
+
 /// An iterator for entries in a database matching a given pattern.
+///
+/// Frames are only searched once the matches of the previous frames have been consumed, so
+/// dropping the iterator after the first few matches avoids searching the rest of the database.
 pub struct ReaderIter<'a, 'b> {
     /// The underlying reader from which we read input.
     reader: Reader,
-    /// Entries that matched the pattern but have not been returned by `next` yet.
-    found: Vec<(StorePath, FileTreeEntry)>,
-    /// Entries that matched the pattern but for which we don't know yet what package they belong to.
-    /// This may happen if the entry we matched was at the end of the search buffer, so that the entry
-    /// for the package did not fit into the buffer anymore (since the package is stored after the entries
-    /// of the package). In this case, we need to look for the package entry in the next iteration when
-    /// we read the next block of input.
-    found_without_package: Vec<FileTreeEntry>,
+    /// The indices of the frames that need to be searched.
+    frames: Vec<usize>,
+    /// The index of the next frame that needs to be searched.
+    next_frame: usize,
+    /// Entries that matched the pattern but have not been returned by `next` yet, in database
+    /// order.
+    found: VecDeque<(StorePath, FileTreeEntry)>,
+    /// The conditions that entries need to satisfy.
+    matcher: EntryMatcher<'a, 'b>,
+}
+
+/// Finds the entries matching the conditions of a query in decoded blocks of the database.
+///
+/// This holds no mutable state, so it can be shared between threads searching different frames.
+struct EntryMatcher<'a, 'b> {
     /// The pattern for which to search package paths.
     ///
     /// This pattern should work on the raw bytes of file entries. In particular, the file path is not the
@@ -287,8 +1900,20 @@ pub struct ReaderIter<'a, 'b> {
     package_entry_pattern: grep::regex::RegexMatcher,
     /// Pattern that the package name should match.
     package_name_pattern: Option<&'b Regex>,
+    /// Pattern that the attribute of the package should match.
+    package_attr_pattern: Option<&'b Regex>,
+    /// Pattern that the output of the package should match.
+    package_output_pattern: Option<&'b Regex>,
     /// Only search the package with the given hash.
     package_hash: Option<String>,
+    /// Only search packages for the given system.
+    package_system: Option<String>,
+    /// Only match regular files that are at least this large.
+    min_size: Option<u64>,
+    /// Only match regular files that are at most this large.
+    max_size: Option<u64>,
+    /// Pattern that the target of symlinks should match. Other files do not match if this is set.
+    target_pattern: Option<&'b Regex>,
 }
 
 fn consume_no_error<T>(e: NoError) -> T {
@@ -333,134 +1958,225 @@ fn next_matching_line<M: Matcher<Error = NoError>>(
     None
 }
 
-impl<'a, 'b> ReaderIter<'a, 'b> {
-    /// Reads input until `self.found` contains at least one entry or the end of the input has been reached.
-    fn fill_buf(&mut self) -> Result<()> {
-        // the input is processed in blocks until we've found at least a single entry
-        while self.found.is_empty() {
-            let &mut ReaderIter {
-                ref mut reader,
-                ref package_entry_pattern,
-                ref package_name_pattern,
-                ref package_hash,
-                ..
-            } = self;
-            let block = reader.decoder.decode()?;
-
-            // if the block is empty, the end of input has been reached
-            if block.is_empty() {
-                return Ok(());
+impl EntryMatcher<'_, '_> {
+    /// Tests if a file matches the `min_size`, `max_size` and `target_pattern` constraints.
+    fn matches_node(&self, node: &FileNode<()>) -> bool {
+        let matches_size = match *node {
+            _ if self.min_size.is_none() && self.max_size.is_none() => true,
+            FileNode::Regular { size, .. } => {
+                self.min_size.is_none_or(|min| size >= min)
+                    && self.max_size.is_none_or(|max| size <= max)
             }
+            _ => false,
+        };
+        let matches_target = match (self.target_pattern, node) {
+            (None, _) => true,
+            (Some(pattern), FileNode::Symlink { target }) => pattern.is_match(target),
+            (Some(_), _) => false,
+        };
+        matches_size && matches_target
+    }
 
-            // when we find a match, we need to know the package that this match belongs to.
-            // the `find_package` function will skip forward until a package entry is found
-            // (the package entry comes after all file entries for a package).
-            //
-            // to be more efficient if there are many matches, we cache the current package here.
-            // this package is valid for all positions up to the second element of the tuple
-            // (after that, a new package begins).
-            let mut cached_package: Option<(StorePath, usize)> = None;
-            let mut no_more_package = false;
-            let mut find_package = |item_end| -> Result<_> {
-                if let Some((ref pkg, end)) = cached_package {
-                    if item_end < end {
-                        return Ok(Some((pkg.clone(), end)));
-                    }
-                }
+    /// Searches all entries of a frame, returning the matching entries.
+    fn search_frame<R: BufRead>(
+        &self,
+        mut decoder: frcode::Decoder<R>,
+    ) -> Result<Vec<(StorePath, FileTreeEntry)>> {
+        let mut found = Vec::new();
+        // Entries that matched the pattern but for which we don't know yet what package they belong to.
+        // This may happen if the entry we matched was at the end of the search buffer, so that the entry
+        // for the package did not fit into the buffer anymore (since the package is stored after the entries
+        // of the package). In this case, we need to look for the package entry in the next iteration when
+        // we read the next block of input.
+        let mut found_without_package = Vec::new();
+        loop {
+            let block = decoder.decode()?;
 
-                if no_more_package {
-                    return Ok(None);
+            // if the block is empty, the end of the frame has been reached. Frames always end
+            // with a package entry, so matches without a package mean that the frame is corrupt
+            if block.is_empty() {
+                if !found_without_package.is_empty() {
+                    return Err(Error::MissingPackageEntry);
                 }
+                return Ok(found);
+            }
 
-                let mat = match next_matching_line(package_entry_pattern, block, item_end) {
-                    Some(v) => v,
-                    None => {
-                        no_more_package = true;
-                        return Ok(None);
-                    }
-                };
+            self.search_block(block, &mut found, &mut found_without_package)?;
+        }
+    }
 
-                let json = &block[mat.start() + 2..mat.end() - 1];
-                let pkg: StorePath =
-                    serde_json::from_slice(json).map_err(|_| Error::StorePathParse {
-                        path: json.to_vec(),
-                    })?;
-                cached_package = Some((pkg.clone(), mat.end()));
-                Ok(Some((pkg, mat.end())))
-            };
+    /// Searches all entries of a frame that was already decoded, returning the matching entries.
+    fn search_decoded(&self, frame: &[u8]) -> Result<Vec<(StorePath, FileTreeEntry)>> {
+        // frames always end with a package entry, so anything after the last one is corrupt
+        let last_line = frame.strip_suffix(b"\n").unwrap_or(frame);
+        let last_line = last_line.rsplit(|c| *c == b'\n').next().unwrap_or_default();
+        if !frame.is_empty() && !last_line.starts_with(b"p\0") {
+            return Err(Error::MissingPackageEntry);
+        }
 
-            // Tests if a store path matches the `package_name_pattern` and `package_hash` constraints.
-            let should_search_package = |pkg: &StorePath| -> bool {
-                package_name_pattern.is_none_or(|r| r.is_match(pkg.name().as_bytes()))
-                    && package_hash.as_ref().is_none_or(|h| h == &pkg.hash())
-            };
+        let mut found = Vec::new();
+        self.search_block(frame, &mut found, &mut Vec::new())?;
+        Ok(found)
+    }
 
-            let mut pos = 0;
-            // if there are any entries without a package left over from the previous iteration, see
-            // if this block contains the package entry.
-            if !self.found_without_package.is_empty() {
-                if let Some((pkg, end)) = find_package(0)? {
-                    if !should_search_package(&pkg) {
-                        // all entries before end will have the same package
-                        pos = end;
-                        self.found_without_package.truncate(0);
-                    } else {
-                        for entry in self.found_without_package.split_off(0) {
-                            self.found.push((pkg.clone(), entry));
-                        }
-                    }
+    /// Searches a single block of decoded entries, adding all matches to `found`.
+    fn search_block(
+        &self,
+        block: &[u8],
+        found: &mut Vec<(StorePath, FileTreeEntry)>,
+        found_without_package: &mut Vec<FileTreeEntry>,
+    ) -> Result<()> {
+        let EntryMatcher {
+            package_entry_pattern,
+            package_name_pattern,
+            package_attr_pattern,
+            package_output_pattern,
+            package_hash,
+            package_system,
+            ..
+        } = self;
+
+        // when we find a match, we need to know the package that this match belongs to.
+        // the `find_package` function will skip forward until a package entry is found
+        // (the package entry comes after all file entries for a package).
+        //
+        // to be more efficient if there are many matches, we cache the current package here.
+        // this package is valid for all positions up to the second element of the tuple
+        // (after that, a new package begins).
+        let mut cached_package: Option<(StorePath, usize)> = None;
+        let mut no_more_package = false;
+        let mut find_package = |item_end| -> Result<_> {
+            if let Some((ref pkg, end)) = cached_package {
+                if item_end < end {
+                    return Ok(Some((pkg.clone(), end)));
                 }
             }
 
-            // process all matches in this block
-            while let Some(mat) = next_matching_line(&self.pattern, block, pos) {
-                pos = mat.end();
-                let entry = &block[mat.start()..mat.end() - 1];
-                // skip entries that aren't describing file paths
-                if self
-                    .package_entry_pattern
-                    .is_match(entry)
-                    .unwrap_or_else(consume_no_error)
-                {
-                    continue;
+            if no_more_package {
+                return Ok(None);
+            }
+
+            let mat = match next_matching_line(package_entry_pattern, block, item_end) {
+                Some(v) => v,
+                None => {
+                    no_more_package = true;
+                    return Ok(None);
                 }
+            };
 
-                // skip if package name or hash doesn't match
-                // we can only skip if we know the package
-                if let Some((pkg, end)) = find_package(mat.end())? {
-                    if !should_search_package(&pkg) {
-                        // all entries before end will have the same package
-                        pos = end;
-                        continue;
+            let pkg = decode_package(&block[mat.start() + 2..mat.end() - 1])?;
+            cached_package = Some((pkg.clone(), mat.end()));
+            Ok(Some((pkg, mat.end())))
+        };
+
+        // Tests if a store path matches the `package_*` constraints.
+        let should_search_package = |pkg: &StorePath| -> bool {
+            let origin = pkg.origin();
+            package_name_pattern.is_none_or(|r| r.is_match(pkg.name().as_bytes()))
+                && package_attr_pattern.is_none_or(|r| r.is_match(origin.attr.as_bytes()))
+                && package_output_pattern.is_none_or(|r| r.is_match(origin.output.as_bytes()))
+                && package_hash.as_ref().is_none_or(|h| h == &pkg.hash())
+                && package_system
+                    .as_ref()
+                    .is_none_or(|system| origin.system.as_ref().is_none_or(|s| s == system))
+        };
+
+        let mut pos = 0;
+        // if there are any entries without a package left over from the previous iteration, see
+        // if this block contains the package entry.
+        if !found_without_package.is_empty() {
+            if let Some((pkg, end)) = find_package(0)? {
+                if !should_search_package(&pkg) {
+                    // all entries before end will have the same package
+                    pos = end;
+                    found_without_package.truncate(0);
+                } else {
+                    for entry in found_without_package.split_off(0) {
+                        found.push((pkg.clone(), entry));
                     }
                 }
+            }
+        }
 
-                let entry = FileTreeEntry::decode(entry).ok_or_else(|| Error::EntryParse {
-                    entry: entry.to_vec(),
-                })?;
+        // process all matches in this block
+        while let Some(mat) = next_matching_line(&self.pattern, block, pos) {
+            pos = mat.end();
+            let entry = &block[mat.start()..mat.end() - 1];
+            // skip entries that aren't describing file paths
+            if self
+                .package_entry_pattern
+                .is_match(entry)
+                .unwrap_or_else(consume_no_error)
+            {
+                continue;
+            }
 
-                // check for false positives
-                if !self.exact_pattern.is_match(&entry.path) {
+            // skip if package name or hash doesn't match
+            // we can only skip if we know the package
+            if let Some((pkg, end)) = find_package(mat.end())? {
+                if !should_search_package(&pkg) {
+                    // all entries before end will have the same package
+                    pos = end;
                     continue;
                 }
+            }
 
-                match find_package(mat.end())? {
-                    None => self.found_without_package.push(entry),
-                    Some((pkg, _)) => self.found.push((pkg, entry)),
-                }
+            let entry = FileTreeEntry::decode(entry).ok_or_else(|| Error::EntryParse {
+                entry: entry.to_vec(),
+            })?;
+
+            // check for false positives
+            if !self.exact_pattern.is_match(&entry.path) || !self.matches_node(&entry.node) {
+                continue;
+            }
+
+            match find_package(mat.end())? {
+                None => found_without_package.push(entry),
+                Some((pkg, _)) => found.push((pkg, entry)),
             }
         }
         Ok(())
     }
+}
+
+impl ReaderIter<'_, '_> {
+    /// Reads input until `self.found` contains at least one entry or the end of the input has been reached.
+    fn fill_buf(&mut self) -> Result<()> {
+        let &mut ReaderIter {
+            ref reader,
+            ref frames,
+            ref mut next_frame,
+            ref mut found,
+            ref matcher,
+        } = self;
+
+        // the frames are searched in batches until we've found at least a single entry. All frames
+        // of a batch are searched in parallel.
+        while found.is_empty() && *next_frame < frames.len() {
+            let batch_end = cmp::min(*next_frame + rayon::current_num_threads(), frames.len());
+            let batch = &frames[*next_frame..batch_end];
+            *next_frame = batch_end;
+
+            let results = batch
+                .par_iter()
+                .map(|&frame| match &reader.cache {
+                    Some(cache) => matcher.search_decoded(cache.frame(frame)),
+                    None => matcher.search_frame(reader.frame_decoder(&reader.frames[frame])?),
+                })
+                .collect::<Result<Vec<_>>>()?;
+            found.extend(results.into_iter().flatten());
+        }
+        Ok(())
+    }
 
     /// Returns the next match in the database.
     fn next_match(&mut self) -> Result<Option<(StorePath, FileTreeEntry)>> {
         self.fill_buf()?;
-        Ok(self.found.pop())
+        Ok(self.found.pop_front())
     }
 }
 
-impl<'a, 'b> Iterator for ReaderIter<'a, 'b> {
+impl Iterator for ReaderIter<'_, '_> {
     type Item = Result<(StorePath, FileTreeEntry)>;
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -473,7 +2189,602 @@ impl<'a, 'b> Iterator for ReaderIter<'a, 'b> {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use serde_bytes::ByteBuf;
+
     use super::*;
+    use crate::testing::{
+        bin_tree, store_path, store_path_for_system, symlink_packages, TestDatabase,
+    };
+
+    #[test]
+    fn test_write_and_search() {
+        let database = TestDatabase::build("write-and-search", |writer| {
+            writer
+                .add(store_path("hello"), bin_tree(&["hello"]), b"")
+                .expect("package can be added");
+            writer
+                .add(store_path("empty"), bin_tree(&["world"]), b"/lib")
+                .expect("package can be added");
+            writer
+                .add(store_path("both"), bin_tree(&["hello", "world"]), b"")
+                .expect("package can be added");
+            writer.set_metadata(Metadata {
+                nixpkgs: Some("<nixpkgs>".to_string()),
+                ..Metadata::default()
+            });
+        });
+
+        let reader = database.reader();
+        let metadata = reader.metadata().expect("database has metadata");
+        assert_eq!(metadata.nixpkgs.as_deref(), Some("<nixpkgs>"));
+        assert_eq!(metadata.packages, 2);
+        assert_eq!(metadata.entries, 7);
+
+        assert_eq!(
+            database.search("hello$"),
+            vec![
+                ("both".to_string(), b"/bin/hello".to_vec()),
+                ("hello".to_string(), b"/bin/hello".to_vec()),
+            ]
+        );
+        assert_eq!(
+            database.search("^/bin/world"),
+            vec![("both".to_string(), b"/bin/world".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_dictionary() {
+        let names = (0..500).map(|i| format!("program-{i}")).collect::<Vec<_>>();
+        let database = TestDatabase::build("dictionary", |writer| {
+            writer.set_dictionary_size(1024);
+            writer.set_long_distance_matching(true);
+            for name in &names {
+                writer
+                    .add(store_path(name), bin_tree(&[name, "common"]), b"")
+                    .expect("package can be added");
+            }
+        });
+
+        let reader = database.reader();
+        assert!(!reader.dictionary.is_empty());
+        reader.verify().expect("database is valid");
+        assert_eq!(
+            database.search("/program-42$"),
+            vec![("program-42".to_string(), b"/bin/program-42".to_vec())]
+        );
+        assert_eq!(database.search("/common$").len(), names.len());
+    }
+
+    #[test]
+    fn test_basename_index() {
+        let database = TestDatabase::new(
+            "basename-index",
+            &[
+                (store_path("hello"), bin_tree(&["hello"])),
+                (store_path("both"), bin_tree(&["hello", "world"])),
+            ],
+        );
+
+        let reader = database.reader();
+        let lookup = |path: &[u8]| {
+            reader
+                .basename_frames(path)
+                .expect("lookup succeeds")
+                .map(|frames| frames.len())
+        };
+        assert_eq!(lookup(b"/bin/world"), Some(1));
+        assert_eq!(lookup(b"/bin/missing"), Some(0));
+        assert_eq!(lookup(b"/share/world"), None);
+        assert_eq!(lookup(b"/bin"), None);
+
+        assert_eq!(
+            database.search("^/bin/hello$"),
+            vec![
+                ("both".to_string(), b"/bin/hello".to_vec()),
+                ("hello".to_string(), b"/bin/hello".to_vec()),
+            ]
+        );
+        assert_eq!(database.search("^/bin/missing$"), vec![]);
+    }
+
+    #[test]
+    fn test_required_literals() {
+        let literals = |pattern: &str| {
+            let expr = regex_syntax::ast::parse::Parser::new()
+                .parse(pattern)
+                .expect("valid regex");
+            required_literals(&expr)
+                .into_iter()
+                .map(|l| String::from_utf8(l).expect("valid utf-8"))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(literals("bin/python3"), vec!["bin/python3"]);
+        assert_eq!(literals(r"^/lib/libssl\.so\..*$"), vec!["/lib/libssl.so."]);
+        assert_eq!(literals("lib(foo|bar)+x?y"), vec!["lib", "y"]);
+        assert_eq!(literals("(?i)libssl"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_packages() {
+        let database = TestDatabase::new(
+            "packages",
+            &[
+                (store_path("hello"), bin_tree(&["hello"])),
+                (store_path("both"), bin_tree(&["hello", "world"])),
+            ],
+        );
+
+        let packages = database
+            .reader()
+            .packages()
+            .map(|r| {
+                let (pkg, entries) = r.expect("package can be read");
+                (pkg, FileTree::from_list(entries))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            packages,
+            vec![
+                (store_path("hello"), bin_tree(&["hello"])),
+                (store_path("both"), bin_tree(&["hello", "world"])),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_package_order() {
+        let names = ["hello", "both", "world"];
+        let write = |name: &str, order: PackageOrder, reversed: bool| {
+            let database = TestDatabase::build(name, |writer| {
+                writer.set_package_order(order);
+                writer.set_created(0);
+                let mut packages = names.to_vec();
+                if reversed {
+                    packages.reverse();
+                }
+                for name in packages {
+                    writer
+                        .add(store_path(name), bin_tree(&[name]), b"")
+                        .expect("package can be added");
+                }
+            });
+            let contents = fs::read(database.path()).expect("database can be read");
+            let packages = database
+                .reader()
+                .packages()
+                .map(|r| r.expect("package can be read").0.name().into_owned())
+                .collect::<Vec<_>>();
+            (contents, packages)
+        };
+
+        let (forward, packages) = write("order-forward", PackageOrder::Attr, false);
+        let (reversed, _) = write("order-reversed", PackageOrder::Attr, true);
+        assert_eq!(forward, reversed);
+        assert_eq!(packages, vec!["both", "hello", "world"]);
+
+        let (_, packages) = write("order-added", PackageOrder::Added, true);
+        assert_eq!(packages, vec!["world", "both", "hello"]);
+    }
+
+    #[test]
+    fn test_reproducible_dictionary() {
+        // the database must not depend on how many frames are compressed in parallel, which
+        // is the number of CPUs of the machine that builds it
+        let write = |name: &str, parallel_frames: usize| {
+            let database = TestDatabase::build(name, |writer| {
+                writer.frame_size = 16 * 1024;
+                writer.parallel_frames = parallel_frames;
+                writer.set_dictionary_size(1024);
+                writer.set_package_order(PackageOrder::Attr);
+                writer.set_created(0);
+                for i in 0..2000 {
+                    let name = format!("program-{i}");
+                    writer
+                        .add(store_path(&name), bin_tree(&[&name, "common"]), b"")
+                        .expect("package can be added");
+                }
+            });
+            let reader = database.reader();
+            assert!(reader.frames.len() > 8);
+            assert!(!reader.dictionary.is_empty());
+            fs::read(database.path()).expect("database can be read")
+        };
+
+        assert!(write("parallel-one", 1) == write("parallel-eight", 8));
+    }
+
+    #[test]
+    fn test_cache() {
+        let pattern = Regex::new("^/bin/").expect("valid regex");
+        let query = |reader: Reader| {
+            let mut results = reader
+                .query(&pattern)
+                .run()
+                .expect("query can be run")
+                .map(|r| {
+                    let (pkg, entry) = r.expect("entry can be read");
+                    (pkg.name().into_owned(), entry.path)
+                })
+                .collect::<Vec<_>>();
+            results.sort();
+            results
+        };
+        let search_cached = |database: &TestDatabase, cache_path: &Path| {
+            let mut reader = database.reader();
+            reader.use_cache(cache_path).expect("cache can be used");
+            query(reader)
+        };
+
+        let database = TestDatabase::new(
+            "cache-first",
+            &[(store_path("hello"), bin_tree(&["hello"]))],
+        );
+        let cache_path = database.cache_path();
+        // the first search creates the cache, the second one uses it
+        assert_eq!(
+            search_cached(&database, &cache_path),
+            query(database.reader())
+        );
+        assert_eq!(
+            search_cached(&database, &cache_path),
+            query(database.reader())
+        );
+
+        // the cache is recreated for a different database
+        let changed = TestDatabase::new(
+            "cache-second",
+            &[(store_path("world"), bin_tree(&["world"]))],
+        );
+        assert_eq!(
+            search_cached(&changed, &cache_path),
+            vec![("world".to_string(), b"/bin/world".to_vec())]
+        );
+
+        // entries after the last package of a frame are reported as corruption, both with and
+        // without the cache
+        let corrupt = TestDatabase::create("cache-corrupt", |path| write_version_1(path, true));
+        let mut reader = corrupt.reader();
+        let streamed = reader
+            .clone()
+            .query(&pattern)
+            .run()
+            .expect("query can be run")
+            .collect::<Vec<_>>();
+        assert!(matches!(streamed[..], [Err(Error::MissingPackageEntry)]));
+        reader
+            .use_cache(corrupt.cache_path())
+            .expect("cache can be used");
+        let cached = reader
+            .query(&pattern)
+            .run()
+            .expect("query can be run")
+            .collect::<Vec<_>>();
+        assert!(matches!(cached[..], [Err(Error::MissingPackageEntry)]));
+    }
+
+    #[test]
+    fn test_merge() {
+        let aarch64 = store_path_for_system("aarch64-linux", "7fzwp5z1nqy7rd6ygldrskm3dq6nhsmz");
+        let first = TestDatabase::new(
+            "merge-first",
+            &[(store_path("hello"), bin_tree(&["hello"]))],
+        );
+        let second = TestDatabase::new(
+            "merge-second",
+            &[
+                (store_path("hello"), bin_tree(&["hello"])),
+                (aarch64.clone(), bin_tree(&["hello"])),
+            ],
+        );
+
+        let inputs = [first.reader(), second.reader()];
+        let merged = TestDatabase::build("merge-merged", |writer| {
+            assert_eq!(merge(&inputs, writer).expect("merge succeeds"), 2);
+        });
+
+        let merged = merged.reader();
+        let packages = merged
+            .packages()
+            .map(|r| r.expect("package can be read").0)
+            .collect::<Vec<_>>();
+        assert_eq!(packages, vec![store_path("hello"), aarch64]);
+        assert_eq!(
+            merged.metadata().expect("database has metadata").systems,
+            vec!["aarch64-linux".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_system() {
+        let database = TestDatabase::new(
+            "system",
+            &[
+                (
+                    store_path_for_system("x86_64-linux", "010yd8jls8w4vcnql4zhjbnyp2yay5pl"),
+                    bin_tree(&["hello"]),
+                ),
+                (
+                    store_path_for_system("other-system", "7fzwp5z1nqy7rd6ygldrskm3dq6nhsmz"),
+                    bin_tree(&["hello"]),
+                ),
+            ],
+        );
+        assert_eq!(
+            database
+                .reader()
+                .metadata()
+                .expect("database has metadata")
+                .systems,
+            vec!["other-system".to_string(), "x86_64-linux".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_size() {
+        let tree = FileTree::directory(HashMap::from([
+            (ByteBuf::from("small"), FileTree::regular(100, false)),
+            (ByteBuf::from("large"), FileTree::regular(10_000, false)),
+            (
+                ByteBuf::from("link"),
+                FileTree::symlink(ByteBuf::from("large")),
+            ),
+        ]));
+        let database = TestDatabase::new("size", &[(store_path("hello"), tree)]);
+
+        let pattern = Regex::new("/").expect("valid regex");
+        let query = |min_size: Option<u64>, max_size: Option<u64>| {
+            let mut paths = database
+                .reader()
+                .query(&pattern)
+                .min_size(min_size)
+                .max_size(max_size)
+                .run()
+                .expect("query can be run")
+                .map(|r| r.expect("entry can be read").1.path)
+                .collect::<Vec<_>>();
+            paths.sort();
+            paths
+        };
+        assert_eq!(query(None, None).len(), 3);
+        assert_eq!(query(Some(1000), None), vec![b"/large".to_vec()]);
+        assert_eq!(query(None, Some(1000)), vec![b"/small".to_vec()]);
+        assert_eq!(
+            query(Some(100), Some(10_000)),
+            vec![b"/large".to_vec(), b"/small".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_target() {
+        let tree = FileTree::directory(HashMap::from([
+            (
+                ByteBuf::from("libGL.so"),
+                FileTree::symlink(ByteBuf::from("/run/opengl-driver/lib/libGL.so")),
+            ),
+            (
+                ByteBuf::from("libfoo.so"),
+                FileTree::symlink(ByteBuf::from("libfoo.so.1")),
+            ),
+            (ByteBuf::from("opengl-driver"), FileTree::regular(10, false)),
+        ]));
+        let database = TestDatabase::new("target", &[(store_path("mesa"), tree)]);
+
+        let query = |pattern: &str, target: &str| {
+            let pattern = Regex::new(pattern).expect("valid regex");
+            let target = Regex::new(target).expect("valid regex");
+            let mut paths = database
+                .reader()
+                .query(&pattern)
+                .target_pattern(Some(&target))
+                .run()
+                .expect("query can be run")
+                .map(|r| r.expect("entry can be read").1.path)
+                .collect::<Vec<_>>();
+            paths.sort();
+            paths
+        };
+        assert_eq!(query("", "/run/opengl-driver"), vec![b"/libGL.so".to_vec()]);
+        assert_eq!(
+            query("", "\\.so"),
+            vec![b"/libGL.so".to_vec(), b"/libfoo.so".to_vec()]
+        );
+        assert_eq!(query("foo", "\\.so"), vec![b"/libfoo.so".to_vec()]);
+        // only symlinks match a target pattern
+        assert!(query("opengl", "").is_empty());
+    }
+
+    #[test]
+    fn test_resolver() {
+        let packages = symlink_packages();
+        let database = TestDatabase::new("resolve", &packages);
+
+        let executable = Resolved::Found(FileNode::Regular {
+            size: 10,
+            executable: true,
+        });
+        let wrapper_root = packages[1].0.as_str().into_owned();
+        let resolved = Resolver::new(database.reader(), "/nix/store")
+            .resolve_all(vec![
+                format!("{}/bin/hello", wrapper_root).into_bytes(),
+                format!("{}/bin/data", wrapper_root).into_bytes(),
+                format!("{}/bin/opengl", wrapper_root).into_bytes(),
+                format!("{}/bin/loop", wrapper_root).into_bytes(),
+                b"/nix/store/00000000000000000000000000000000-missing/bin/hello".to_vec(),
+            ])
+            .expect("paths can be resolved");
+        assert_eq!(
+            resolved,
+            vec![
+                // `/libexec/hello` has no entry, since `/libexec` is a symlink to a directory
+                executable,
+                Resolved::Found(FileNode::Regular {
+                    size: 10,
+                    executable: false,
+                }),
+                Resolved::External(b"/run/opengl-driver/bin/x".to_vec()),
+                Resolved::Loop,
+                Resolved::External(
+                    b"/nix/store/00000000000000000000000000000000-missing/bin/hello".to_vec()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_package_entries() {
+        let database = TestDatabase::new(
+            "package-entries",
+            &[
+                (store_path("hello"), bin_tree(&["hello", "common"])),
+                (store_path("world"), bin_tree(&["world", "common"])),
+            ],
+        );
+
+        let reader = database.reader();
+        let packages = reader
+            .package_entries(|store_path| store_path.origin().attr == "world")
+            .expect("packages can be read");
+        assert_eq!(packages.len(), 1);
+        let (found, entries) = &packages[0];
+        assert_eq!(found, &store_path("world"));
+        let mut paths = entries
+            .iter()
+            .map(|entry| String::from_utf8_lossy(&entry.path).into_owned())
+            .collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(paths, vec!["", "/bin", "/bin/common", "/bin/world"]);
+
+        let tree = FileTree::from_list(packages.into_iter().next().expect("one package").1);
+        let names = |tree: &FileTree| {
+            tree.children()
+                .into_iter()
+                .map(|(name, _)| String::from_utf8_lossy(name).into_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(&tree), vec!["bin"]);
+        assert_eq!(names(tree.children()[0].1), vec!["common", "world"]);
+    }
+
+    #[test]
+    fn test_attr_output() {
+        let database = TestDatabase::new(
+            "attr-output",
+            &[(store_path("hello"), bin_tree(&["hello"]))],
+        );
+        let pattern = Regex::new("hello").expect("valid regex");
+        let count = |attr: &str, output: &str| {
+            let attr = Regex::new(attr).expect("valid regex");
+            let output = Regex::new(output).expect("valid regex");
+            database
+                .reader()
+                .query(&pattern)
+                .attr_pattern(Some(&attr))
+                .output_pattern(Some(&output))
+                .run()
+                .expect("query can be run")
+                .count()
+        };
+        assert_eq!(count("^hel", "^out$"), 1);
+        assert_eq!(count("^world", "^out$"), 0);
+        assert_eq!(count("^hel", "^dev$"), 0);
+    }
+
+    #[test]
+    fn test_verify() {
+        let database = TestDatabase::new(
+            "verify",
+            &[
+                (store_path("hello"), bin_tree(&["hello"])),
+                (store_path("both"), bin_tree(&["hello", "world"])),
+            ],
+        );
+
+        let reader = database.reader();
+        assert_eq!(
+            reader.verify().expect("database is valid"),
+            Verification {
+                frames: 1,
+                packages: 2,
+                entries: 7,
+            }
+        );
+
+        // corrupt a byte in the middle of the frame
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .open(database.path())
+            .expect("database can be opened");
+        file.write_all_at(b"X", HEADER_LEN + 8)
+            .expect("write succeeds");
+        assert!(matches!(
+            database.reader().verify(),
+            Err(Error::FrameChecksumMismatch { offset: HEADER_LEN })
+        ));
+    }
+
+    #[test]
+    fn test_unfinished_writer_keeps_database() {
+        let database =
+            TestDatabase::new("unfinished", &[(store_path("hello"), bin_tree(&["hello"]))]);
+
+        let mut writer = Writer::create(database.path(), 3).expect("database can be created");
+        writer
+            .add(store_path("world"), bin_tree(&["world"]), b"")
+            .expect("package can be added");
+        drop(writer);
+
+        let temp = format!("{}.tmp", database.path().display());
+        assert!(!PathBuf::from(temp).exists());
+        assert_eq!(
+            database.search("/bin/"),
+            vec![("hello".to_string(), b"/bin/hello".to_vec())]
+        );
+    }
+
+    /// Writes a database in format version 1 that contains the package `hello`. If `corrupt` is
+    /// set, the package is followed by an entry that belongs to no package.
+    fn write_version_1(path: &Path, corrupt: bool) {
+        let mut file = File::create(path).expect("file can be created");
+        file.write_all(FILE_MAGIC).expect("write succeeds");
+        file.write_u64::<LittleEndian>(1).expect("write succeeds");
+        let mut encoder = zstd::Encoder::new(file, 3).expect("encoder can be created");
+        {
+            let mut frcode = frcode::Encoder::new(
+                &mut encoder,
+                b"p".to_vec(),
+                serde_json::to_vec(&store_path("hello")).expect("path can be serialized"),
+            );
+            for entry in bin_tree(&["hello"]).to_list(b"") {
+                entry.encode(&mut frcode).expect("entry can be encoded");
+            }
+            frcode.finish().expect("encoder can be finished");
+        }
+        if corrupt {
+            // the last entry of an encoder is meant to be the package, but may be any entry
+            frcode::Encoder::new(&mut encoder, b"10x".to_vec(), b"/bin/extra".to_vec())
+                .finish()
+                .expect("encoder can be finished");
+        }
+        encoder.finish().expect("encoder can be finished");
+    }
+
+    #[test]
+    fn test_read_version_1() {
+        let database = TestDatabase::create("read-version-1", |path| write_version_1(path, false));
+
+        let reader = database.reader();
+        assert_eq!(reader.format_version(), 1);
+        assert_eq!(reader.metadata(), None);
+        assert_eq!(
+            database.search("hello"),
+            vec![("hello".to_string(), b"/bin/hello".to_vec())]
+        );
+    }
 
     #[test]
     fn test_next_matching_line_package() {
@@ -0,0 +1,192 @@
+//! Comparing the contents of two databases.
+//!
+//! This module finds the files that were added, removed or changed between two databases,
+//! for example to see how the files of packages changed after updating nixpkgs.
+//!
+//! Packages are compared by their attribute path, output and system. Only top-level packages
+//! are compared, because for other packages, the attribute path does not identify the package
+//! (see the documentation of `PathOrigin`).
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::database::{Error, Reader};
+use crate::files::{FileNode, FileTreeEntry};
+use crate::package::{PathOrigin, StorePath};
+
+/// The change of a single file between two databases.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FileChange {
+    /// The file only exists in the new database.
+    Added(FileNode<()>),
+    /// The file only exists in the old database.
+    Removed(FileNode<()>),
+    /// The file exists in both databases, but its type, size or symlink target changed.
+    Changed {
+        old: FileNode<()>,
+        new: FileNode<()>,
+    },
+}
+
+/// The changes of the files of a single package.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PackageChanges {
+    /// The attribute path, output and system that identify the package.
+    pub origin: PathOrigin,
+    /// The store path of the package in the old database, if the package existed there.
+    pub old: Option<StorePath>,
+    /// The store path of the package in the new database, if the package exists there.
+    pub new: Option<StorePath>,
+    /// The changed files, sorted by path.
+    pub changes: Vec<(Vec<u8>, FileChange)>,
+}
+
+/// Returns true if the node changed in a way that should be reported.
+///
+/// The size of a directory is the number of its children, which changes whenever a file is
+/// added or removed. Since that is already reported for the files themselves, directories
+/// are only compared by type.
+fn node_changed(old: &FileNode<()>, new: &FileNode<()>) -> bool {
+    match (old, new) {
+        (FileNode::Directory { .. }, FileNode::Directory { .. }) => false,
+        _ => old != new,
+    }
+}
+
+/// Compares the files of a package in the old and new database.
+fn diff_entries(old: Vec<FileTreeEntry>, new: Vec<FileTreeEntry>) -> Vec<(Vec<u8>, FileChange)> {
+    let mut old = old
+        .into_iter()
+        .map(|entry| (entry.path, entry.node))
+        .collect::<BTreeMap<_, _>>();
+
+    let mut changes = Vec::new();
+    for FileTreeEntry { path, node } in new {
+        match old.remove(&path) {
+            None => changes.push((path, FileChange::Added(node))),
+            Some(old) if node_changed(&old, &node) => {
+                changes.push((path, FileChange::Changed { old, new: node }))
+            }
+            Some(_) => {}
+        }
+    }
+    changes.extend(
+        old.into_iter()
+            .map(|(path, node)| (path, FileChange::Removed(node))),
+    );
+    changes.sort_by(|a, b| a.0.cmp(&b.0));
+    changes
+}
+
+/// The key by which packages are matched between the two databases.
+fn package_key(path: &StorePath) -> Option<PathOrigin> {
+    let origin = path.origin();
+    origin.toplevel.then(|| origin.into_owned())
+}
+
+/// Compares two databases, returning the packages whose files changed, sorted by attribute path.
+///
+/// Packages that only exist in one of the databases are reported with all their files as
+/// added or removed.
+///
+/// If a package has the same store path in both databases, its contents cannot have changed,
+/// so only packages whose store path changed need to be compared in detail.
+pub fn diff(old: &Reader, new: &Reader) -> Result<Vec<PackageChanges>, Error> {
+    // Find the store paths of all packages of the new database.
+    let mut new_paths: HashMap<PathOrigin, StorePath> = HashMap::new();
+    for package in new.packages() {
+        let (path, _) = package?;
+        if let Some(key) = package_key(&path) {
+            new_paths.insert(key, path);
+        }
+    }
+
+    // Collect the entries of all packages of the old database that need to be compared.
+    let mut old_packages: HashMap<PathOrigin, (StorePath, Vec<FileTreeEntry>)> = HashMap::new();
+    let mut unchanged: HashSet<PathOrigin> = HashSet::new();
+    for package in old.packages() {
+        let (path, entries) = package?;
+        let Some(key) = package_key(&path) else {
+            continue;
+        };
+        if new_paths.get(&key).is_some_and(|p| p.hash() == path.hash()) {
+            unchanged.insert(key);
+            continue;
+        }
+        old_packages.insert(key, (path, entries));
+    }
+
+    let mut result = Vec::new();
+    for package in new.packages() {
+        let (path, entries) = package?;
+        let Some(key) = package_key(&path) else {
+            continue;
+        };
+        let (old_path, old_entries) = match old_packages.remove(&key) {
+            Some((old_path, old_entries)) => (Some(old_path), old_entries),
+            // the package exists in both databases with the same store path
+            None if unchanged.contains(&key) => continue,
+            None => (None, Vec::new()),
+        };
+        let changes = diff_entries(old_entries, entries);
+        if !changes.is_empty() {
+            result.push(PackageChanges {
+                origin: key,
+                old: old_path,
+                new: Some(path),
+                changes,
+            });
+        }
+    }
+
+    for (key, (old_path, old_entries)) in old_packages {
+        result.push(PackageChanges {
+            origin: key,
+            old: Some(old_path),
+            new: None,
+            changes: diff_entries(old_entries, Vec::new()),
+        });
+    }
+
+    result.sort_by(|a, b| a.origin.cmp(&b.origin));
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{bin_tree, store_path, store_path_with_hash, TestDatabase};
+
+    #[test]
+    fn test_diff() {
+        let updated = store_path_with_hash("both", "7fzwp5z1nqy7rd6ygldrskm3dq6nhsmz");
+        let old = TestDatabase::new(
+            "diff-old",
+            &[
+                (store_path("hello"), bin_tree(&["hello"])),
+                (store_path("both"), bin_tree(&["hello", "world"])),
+            ],
+        );
+        let new = TestDatabase::new(
+            "diff-new",
+            &[
+                (store_path("hello"), bin_tree(&["hello"])),
+                (updated.clone(), bin_tree(&["hello", "planet"])),
+            ],
+        );
+
+        let packages = diff(&old.reader(), &new.reader()).expect("diff succeeds");
+        assert_eq!(packages.len(), 1);
+        assert_eq!(packages[0].old, Some(store_path("both")));
+        assert_eq!(packages[0].new, Some(updated));
+        let regular = FileNode::Regular {
+            size: 10,
+            executable: true,
+        };
+        assert_eq!(
+            packages[0].changes,
+            vec![
+                (b"/bin/planet".to_vec(), FileChange::Added(regular.clone())),
+                (b"/bin/world".to_vec(), FileChange::Removed(regular)),
+            ]
+        );
+    }
+}
@@ -2,7 +2,7 @@ use std::{io, path::PathBuf};
 
 use thiserror::Error;
 
-use crate::{hydra, nixpkgs, package::StorePath};
+use crate::{database, hydra, nixpkgs, package::StorePath, sqlite};
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -50,12 +50,56 @@ pub enum Error {
         #[source]
         source: io::Error,
     },
+    #[error("locking the database with the lock file '{path:?}' failed: {source}")]
+    LockDatabase {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
     #[error("writing to the database '{path:?}' failed: {source}")]
     WriteDatabase {
         path: PathBuf,
         #[source]
         source: io::Error,
     },
+    #[error("reading the database '{path:?}' failed: {source}")]
+    ReadDatabase {
+        path: PathBuf,
+        #[source]
+        source: database::Error,
+    },
+    #[error("merging the databases failed: {source}")]
+    MergeDatabases {
+        #[source]
+        source: database::Error,
+    },
+    #[error("comparing the databases failed: {source}")]
+    DiffDatabases {
+        #[source]
+        source: database::Error,
+    },
+    #[error("writing the differences failed: {source}")]
+    WriteDiff {
+        #[source]
+        source: io::Error,
+    },
+    #[error("verifying the database '{path:?}' failed: {source}")]
+    VerifyDatabase {
+        path: PathBuf,
+        #[source]
+        source: database::Error,
+    },
+    #[error("exporting the database to '{path:?}' failed: {source}")]
+    ExportSqlite {
+        path: PathBuf,
+        #[source]
+        source: sqlite::Error,
+    },
+    #[error("reading the previous database failed: {source}")]
+    ReadPreviousDatabase {
+        #[source]
+        source: database::Error,
+    },
     #[error("Can not parse proxy settings: {0}")]
     ParseProxy(#[from] crate::hydra::Error),
 }
@@ -2,15 +2,15 @@
 //!
 //! The main type here is `FileTree` which represents
 //! such as the file listing for a store path.
-use std::collections::HashMap;
+use std::collections::{hash_map, HashMap, HashSet};
 use std::io::{self, Write};
 use std::str::{self, FromStr};
 
 use clap::builder::PossibleValue;
 use clap::ValueEnum;
 use memchr::memchr;
-use serde::{Deserialize, Serialize};
-use serde_bytes::ByteBuf;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_bytes::{ByteBuf, Bytes};
 
 use crate::frcode;
 
@@ -102,6 +102,23 @@ impl FromStr for FileType {
     }
 }
 
+// File types are serialized as the same letters that are accepted by `FromStr`.
+impl Serialize for FileType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let value = self
+            .to_possible_value()
+            .expect("every file type has a value");
+        serializer.serialize_str(value.get_name())
+    }
+}
+
+impl<'de> Deserialize<'de> for FileType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
 /// This lists all file types that can currently be represented.
 pub const ALL_FILE_TYPES: &[FileType] = &[
     FileType::Regular { executable: true },
@@ -178,6 +195,24 @@ impl FileNode<()> {
     }
 }
 
+/// The maximum number of symlinks that are followed when resolving a path, the same as the
+/// limit of Linux. Resolving a path that needs more symlinks fails with `Resolved::Loop`.
+pub const MAX_SYMLINKS: usize = 40;
+
+/// The result of resolving a path, following all symlinks on the way.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Resolved {
+    /// The path refers to this file or directory.
+    Found(FileNode<()>),
+    /// The path leads out of the file tree, to this absolute path. This happens for symlinks
+    /// to files in other store paths or outside the store, which have to be resolved elsewhere.
+    External(Vec<u8>),
+    /// The path does not exist, for example because a symlink on the way is dangling.
+    Missing,
+    /// More than `MAX_SYMLINKS` symlinks were followed, so there is probably a symlink loop.
+    Loop,
+}
+
 /// This type represents a full tree of files.
 ///
 /// A *file tree* is a *file node* where each directory contains
@@ -229,6 +264,24 @@ impl FileTree {
         })
     }
 
+    /// Returns the node at the root of this tree, without the contents of directories.
+    pub fn node(&self) -> FileNode<()> {
+        self.0.split_contents().0
+    }
+
+    /// Returns the entries of this tree, sorted by name. Only directories have entries.
+    pub fn children(&self) -> Vec<(&[u8], &FileTree)> {
+        let mut children = match &self.0 {
+            FileNode::Directory { contents, .. } => contents
+                .iter()
+                .map(|(name, tree)| (&name[..], tree))
+                .collect(),
+            _ => Vec::new(),
+        };
+        children.sort_by(|a, b| a.0.cmp(b.0));
+        children
+    }
+
     pub fn to_list(&self, filter_prefix: &[u8]) -> Vec<FileTreeEntry> {
         let mut result = Vec::new();
 
@@ -255,4 +308,254 @@ impl FileTree {
         }
         result
     }
+
+    /// Builds a file tree from a list of entries, as returned by `to_list`.
+    ///
+    /// Directories that are missing from the list (for example, because the list was filtered
+    /// with a prefix) are created with the entries that the list has for them, so their size is
+    /// the number of those entries. Directories in the list keep their size.
+    pub fn from_list(entries: Vec<FileTreeEntry>) -> FileTree {
+        let mut root = FileTree::directory(HashMap::new());
+        // the paths of the directories that were created because they are missing from the list
+        let mut missing = HashSet::from([Vec::new()]);
+        for FileTreeEntry { path, node } in entries {
+            let mut current = &mut root;
+            let mut end = 0;
+            // paths are of the form `/a/b`, so the first component is always empty
+            for name in path.split(|c| *c == b'/').skip(1) {
+                end += 1 + name.len();
+                current = match current.make_directory().entry(ByteBuf::from(name)) {
+                    hash_map::Entry::Occupied(e) => e.into_mut(),
+                    hash_map::Entry::Vacant(e) => {
+                        missing.insert(path[..end].to_vec());
+                        e.insert(FileTree::directory(HashMap::new()))
+                    }
+                };
+            }
+            missing.remove(&path);
+
+            match node {
+                FileNode::Regular { size, executable } => {
+                    *current = FileTree::regular(size, executable);
+                }
+                FileNode::Symlink { target } => *current = FileTree::symlink(target),
+                FileNode::Directory { size, contents: () } => {
+                    current.make_directory();
+                    if let FileTree(FileNode::Directory { size: s, .. }) = current {
+                        *s = size;
+                    }
+                }
+            }
+        }
+
+        for path in missing {
+            if let Some(FileTree(FileNode::Directory { size, contents })) = root.get_mut(&path) {
+                *size = contents.len() as u64;
+            }
+        }
+        root
+    }
+
+    /// Returns the node at the given path (of the form `/a/b`), without following symlinks.
+    fn get_mut(&mut self, path: &[u8]) -> Option<&mut FileTree> {
+        let mut current = self;
+        for name in path.split(|c| *c == b'/').skip(1) {
+            current = match current {
+                FileTree(FileNode::Directory { contents, .. }) => {
+                    contents.get_mut(Bytes::new(name))?
+                }
+                _ => return None,
+            };
+        }
+        Some(current)
+    }
+
+    /// Resolves a path inside this tree, following symlinks like the kernel would.
+    ///
+    /// `root` is the absolute path at which the tree is located (usually its store path) and
+    /// `path` is relative to the tree, of the form `/a/b` like the paths returned by `to_list`.
+    /// Relative symlink targets and absolute targets below `root` are resolved inside the tree,
+    /// other targets lead to `Resolved::External`.
+    pub fn resolve(&self, root: &[u8], path: &[u8]) -> Resolved {
+        // the components that still need to be resolved, with the next one at the end
+        let mut pending: Vec<&[u8]> = path.split(|c| *c == b'/').rev().collect();
+        // the directories from the root of the tree to the current node
+        let mut current = vec![self];
+        let mut symlinks = 0;
+
+        while let Some(name) = pending.pop() {
+            let FileTree(node) = current.last().expect("root is never removed");
+            let contents = match node {
+                FileNode::Directory { contents, .. } => contents,
+                // only directories can have children
+                _ => return Resolved::Missing,
+            };
+            match name {
+                b"" | b"." => continue,
+                b".." if current.len() == 1 => {
+                    // the parent of the root is outside of the tree
+                    let parent = root.rsplitn(2, |c| *c == b'/').nth(1).unwrap_or(b"");
+                    return Resolved::External(join(parent, &pending));
+                }
+                b".." => {
+                    current.pop();
+                    continue;
+                }
+                _ => {}
+            }
+
+            let child = match contents.get(Bytes::new(name)) {
+                Some(child) => child,
+                None => return Resolved::Missing,
+            };
+            let target = match &child.0 {
+                FileNode::Symlink { target } => target,
+                _ => {
+                    current.push(child);
+                    continue;
+                }
+            };
+
+            symlinks += 1;
+            if symlinks > MAX_SYMLINKS {
+                return Resolved::Loop;
+            }
+            if target.starts_with(b"/") {
+                match target.strip_prefix(root) {
+                    Some(rest) if rest.is_empty() || rest.starts_with(b"/") => current.truncate(1),
+                    _ => return Resolved::External(join(target, &pending)),
+                }
+                pending.extend(target[root.len()..].split(|c| *c == b'/').rev());
+            } else {
+                pending.extend(target.split(|c| *c == b'/').rev());
+            }
+        }
+
+        Resolved::Found(current.last().expect("root is never removed").node())
+    }
+
+    /// Turns this node into a directory if it is not one already, and returns the
+    /// contents of the directory.
+    fn make_directory(&mut self) -> &mut HashMap<ByteBuf, FileTree> {
+        if !matches!(self.0, FileNode::Directory { .. }) {
+            *self = FileTree::directory(HashMap::new());
+        }
+        match self.0 {
+            FileNode::Directory {
+                ref mut contents, ..
+            } => contents,
+            _ => unreachable!("node was just turned into a directory"),
+        }
+    }
+}
+
+/// Joins `base` and the components of `pending` (which are stored in reverse order) to a path.
+fn join(base: &[u8], pending: &[&[u8]]) -> Vec<u8> {
+    let mut path = base.to_vec();
+    for name in pending.iter().rev().filter(|name| !name.is_empty()) {
+        path.push(b'/');
+        path.extend_from_slice(name);
+    }
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{link, store_path, symlink_packages};
+
+    #[test]
+    fn test_from_list() {
+        let tree = FileTree::directory(HashMap::from([
+            (
+                ByteBuf::from("share"),
+                FileTree::directory(HashMap::from([
+                    (
+                        ByteBuf::from("man"),
+                        FileTree::directory(HashMap::from([
+                            (ByteBuf::from("a.1"), FileTree::regular(1, false)),
+                            (ByteBuf::from("b.1"), FileTree::regular(2, false)),
+                        ])),
+                    ),
+                    (ByteBuf::from("doc"), FileTree::directory(HashMap::new())),
+                ])),
+            ),
+            (ByteBuf::from("bin"), FileTree::directory(HashMap::new())),
+        ]));
+        assert_eq!(FileTree::from_list(tree.to_list(b"")), tree);
+
+        // directories above the prefix are missing from the list, so they only contain the
+        // entries below the prefix
+        let filtered = FileTree::from_list(tree.to_list(b"/share/man"));
+        let size = |tree: &FileTree| match tree.node() {
+            FileNode::Directory { size, .. } => size,
+            _ => panic!("not a directory"),
+        };
+        assert_eq!(size(&filtered), 1);
+        let share = filtered.children()[0].1;
+        assert_eq!(size(share), 1);
+        let man = tree.children()[1].1.children()[1].1;
+        assert_eq!(share.children()[0].1, man);
+
+        // directories in the list keep their size, even if entries below them were filtered
+        let mut entries = tree.to_list(b"/share");
+        entries.retain(|entry| entry.path != b"/share/doc");
+        assert_eq!(size(FileTree::from_list(entries).children()[0].1), 2);
+    }
+
+    #[test]
+    fn test_resolve() {
+        let [(hello, tree), _] = symlink_packages();
+        let root = hello.as_str().into_owned();
+        let resolve = |path: &str| tree.resolve(root.as_bytes(), path.as_bytes());
+        let executable = Resolved::Found(FileNode::Regular {
+            size: 10,
+            executable: true,
+        });
+        assert_eq!(resolve("/bin/hello"), executable);
+        assert_eq!(resolve("/bin/relative"), executable);
+        assert_eq!(resolve("/bin/../libexec/./hello"), executable);
+        assert_eq!(resolve("/bin/dangling"), Resolved::Missing);
+        assert_eq!(resolve("/bin/loop"), Resolved::Loop);
+        assert_eq!(resolve("/bin/hello/foo"), Resolved::Missing);
+        assert_eq!(
+            resolve("/bin/../../other"),
+            Resolved::External(b"/nix/store/other".to_vec())
+        );
+        assert_eq!(
+            resolve(""),
+            Resolved::Found(FileNode::Directory {
+                size: 3,
+                contents: ()
+            })
+        );
+    }
+
+    #[test]
+    fn test_resolve_absolute() {
+        let root = store_path("hello").as_str().into_owned();
+        let tree = FileTree::directory(HashMap::from([
+            (ByteBuf::from("hello"), FileTree::regular(10, true)),
+            (ByteBuf::from("self"), link(&format!("{}/hello", root))),
+            (ByteBuf::from("other"), link("/run/current-system/sw/bin/x")),
+            (ByteBuf::from("dir"), link("/etc")),
+        ]));
+        let resolve = |path: &str| tree.resolve(root.as_bytes(), path.as_bytes());
+        assert_eq!(
+            resolve("/self"),
+            Resolved::Found(FileNode::Regular {
+                size: 10,
+                executable: true
+            })
+        );
+        assert_eq!(
+            resolve("/other"),
+            Resolved::External(b"/run/current-system/sw/bin/x".to_vec())
+        );
+        // the rest of the path is resolved outside of the tree
+        assert_eq!(
+            resolve("/dir/./passwd"),
+            Resolved::External(b"/etc/./passwd".to_vec())
+        );
+    }
 }
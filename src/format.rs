@@ -0,0 +1,515 @@
+//! Machine-readable output formats for the matches of a search.
+//!
+//! Every match is described by the same fields in all formats (see `Field`). File paths and
+//! symlink targets are not necessarily valid UTF-8, so they are written without any loss:
+//!
+//! * CSV, null-separated output and templates write the raw bytes.
+//! * JSON and NDJSON write them as strings if they are valid UTF-8, and as arrays of bytes
+//!   otherwise.
+use std::borrow::Cow;
+use std::io::{self, Write};
+use std::mem;
+use std::str::{self, FromStr};
+
+use clap::ValueEnum;
+use serde::{Serialize, Serializer};
+
+use crate::files::{FileNode, FileTreeEntry, FileType};
+use crate::package::{PathOrigin, StorePath};
+
+/// A machine-readable output format.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// A single JSON array with an object for each match.
+    Json,
+    /// A JSON object for each match, one per line.
+    Ndjson,
+    /// Comma-separated values with a header line.
+    Csv,
+    /// The absolute path of each matching file, terminated by a NUL byte (like `find -print0`).
+    NullSeparated,
+}
+
+/// A field of a match, which can be used as a placeholder in a `Template`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Field {
+    /// The attribute of the package.
+    Attr,
+    /// The output of the package.
+    Output,
+    /// Whether the package is a top-level package (`true` or `false`).
+    Toplevel,
+    /// The system of the package, if known.
+    System,
+    /// The size of a file or the number of entries of a directory. Empty for symlinks.
+    Size,
+    /// The type of the file (`r`, `x`, `d` or `s`).
+    Type,
+    /// The store path of the package.
+    StorePath,
+    /// The path of the file, relative to the store path.
+    Path,
+    /// The target of a symlink. Empty for other files.
+    Target,
+}
+
+/// All fields, in the order in which they are written as CSV.
+const FIELDS: &[Field] = &[
+    Field::Attr,
+    Field::Output,
+    Field::Toplevel,
+    Field::System,
+    Field::StorePath,
+    Field::Path,
+    Field::Type,
+    Field::Size,
+    Field::Target,
+];
+
+impl Field {
+    /// Returns the name of the field, as used for placeholders and CSV headers.
+    pub fn name(self) -> &'static str {
+        match self {
+            Field::Attr => "attr",
+            Field::Output => "output",
+            Field::Toplevel => "toplevel",
+            Field::System => "system",
+            Field::Size => "size",
+            Field::Type => "type",
+            Field::StorePath => "store_path",
+            Field::Path => "path",
+            Field::Target => "target",
+        }
+    }
+
+    /// Writes the value of this field for the given match.
+    fn write<W: Write>(
+        self,
+        out: &mut W,
+        store_path: &StorePath,
+        entry: &FileTreeEntry,
+    ) -> io::Result<()> {
+        let origin = store_path.origin();
+        match self {
+            Field::Attr => out.write_all(origin.attr.as_bytes()),
+            Field::Output => out.write_all(origin.output.as_bytes()),
+            Field::Toplevel => write!(out, "{}", origin.toplevel),
+            Field::System => out.write_all(origin.system.as_deref().unwrap_or("").as_bytes()),
+            Field::Size => match size(&entry.node) {
+                Some(size) => write!(out, "{}", size),
+                None => Ok(()),
+            },
+            Field::Type => out.write_all(type_name(entry.node.get_type()).as_bytes()),
+            Field::StorePath => out.write_all(store_path.as_str().as_bytes()),
+            Field::Path => out.write_all(&entry.path),
+            Field::Target => out.write_all(target(&entry.node).unwrap_or(b"")),
+        }
+    }
+}
+
+impl FromStr for Field {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        FIELDS
+            .iter()
+            .copied()
+            .find(|field| field.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<_> = FIELDS.iter().map(|field| field.name()).collect();
+                format!(
+                    "unknown placeholder '{{{}}}' (possible placeholders: {})",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// A user-defined output format, where placeholders like `{attr}` or `{path}` are replaced by
+/// the fields of each match. Literal braces are written as `{{` and `}}`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Field(Field),
+}
+
+impl FromStr for Template {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut rest = s;
+        while let Some(i) = rest.find(['{', '}']) {
+            literal.push_str(&rest[..i]);
+            let (brace, after) = rest[i..].split_at(1);
+            if after.starts_with(brace) {
+                literal.push_str(brace);
+                rest = &after[1..];
+            } else if brace == "}" {
+                return Err(
+                    "unmatched '}' in template (write '}}' for a literal brace)".to_string()
+                );
+            } else {
+                let end = after
+                    .find('}')
+                    .ok_or("unclosed '{' in template (write '{{' for a literal brace)")?;
+                if !literal.is_empty() {
+                    parts.push(Part::Literal(mem::take(&mut literal)));
+                }
+                parts.push(Part::Field(after[..end].parse()?));
+                rest = &after[end + 1..];
+            }
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(Template { parts })
+    }
+}
+
+impl Template {
+    /// Writes the template for the given match, without a trailing newline.
+    pub fn write<W: Write>(
+        &self,
+        out: &mut W,
+        store_path: &StorePath,
+        entry: &FileTreeEntry,
+    ) -> io::Result<()> {
+        for part in &self.parts {
+            match part {
+                Part::Literal(s) => out.write_all(s.as_bytes())?,
+                Part::Field(field) => field.write(out, store_path, entry)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How a `Writer` writes matches.
+enum Style {
+    Format(Format),
+    /// Each match is written with the template, followed by a newline.
+    Template(Template),
+}
+
+/// Writes matches in a machine-readable format.
+///
+/// Some formats need a header or footer, so `finish` must be called after the last match.
+pub struct Writer<W> {
+    output: W,
+    style: Style,
+    /// The number of matches written so far.
+    count: u64,
+}
+
+impl<W: Write> Writer<W> {
+    /// Creates a writer for the given format.
+    pub fn new(output: W, format: Format) -> Writer<W> {
+        Writer {
+            output,
+            style: Style::Format(format),
+            count: 0,
+        }
+    }
+
+    /// Creates a writer that writes each match with the given template, on its own line.
+    pub fn with_template(output: W, template: Template) -> Writer<W> {
+        Writer {
+            output,
+            style: Style::Template(template),
+            count: 0,
+        }
+    }
+
+    /// Returns the underlying output.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.output
+    }
+
+    /// Writes a single match.
+    pub fn write(&mut self, store_path: &StorePath, entry: &FileTreeEntry) -> io::Result<()> {
+        let out = &mut self.output;
+        match &self.style {
+            Style::Format(Format::Json) => {
+                out.write_all(if self.count == 0 { b"[\n" } else { b",\n" })?;
+                serde_json::to_writer(&mut *out, &JsonMatch::new(store_path, entry))?;
+            }
+            Style::Format(Format::Ndjson) => {
+                serde_json::to_writer(&mut *out, &JsonMatch::new(store_path, entry))?;
+                out.write_all(b"\n")?;
+            }
+            Style::Format(Format::Csv) => {
+                if self.count == 0 {
+                    write_csv_header(out)?;
+                }
+                let mut value = Vec::new();
+                for (i, field) in FIELDS.iter().enumerate() {
+                    if i > 0 {
+                        out.write_all(b",")?;
+                    }
+                    value.clear();
+                    field.write(&mut value, store_path, entry)?;
+                    write_csv_value(out, &value)?;
+                }
+                out.write_all(b"\n")?;
+            }
+            Style::Format(Format::NullSeparated) => {
+                out.write_all(store_path.as_str().as_bytes())?;
+                out.write_all(&entry.path)?;
+                out.write_all(b"\0")?;
+            }
+            Style::Template(template) => {
+                template.write(out, store_path, entry)?;
+                out.write_all(b"\n")?;
+            }
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Writes the footer of the format, if any, and returns the underlying output.
+    pub fn finish(mut self) -> io::Result<W> {
+        match self.style {
+            Style::Format(Format::Json) if self.count == 0 => self.output.write_all(b"[]\n")?,
+            Style::Format(Format::Json) => self.output.write_all(b"\n]\n")?,
+            Style::Format(Format::Csv) if self.count == 0 => write_csv_header(&mut self.output)?,
+            _ => {}
+        }
+        Ok(self.output)
+    }
+}
+
+/// Writes the names of all fields as the header line of CSV output.
+fn write_csv_header<W: Write>(out: &mut W) -> io::Result<()> {
+    let names: Vec<_> = FIELDS.iter().map(|field| field.name()).collect();
+    writeln!(out, "{}", names.join(","))
+}
+
+/// Writes a single CSV value, quoting it if necessary (as described by RFC 4180).
+fn write_csv_value<W: Write>(out: &mut W, value: &[u8]) -> io::Result<()> {
+    if !value
+        .iter()
+        .any(|b| matches!(b, b',' | b'"' | b'\n' | b'\r'))
+    {
+        return out.write_all(value);
+    }
+    out.write_all(b"\"")?;
+    for chunk in value.split_inclusive(|b| *b == b'"') {
+        out.write_all(chunk)?;
+        if chunk.ends_with(b"\"") {
+            out.write_all(b"\"")?;
+        }
+    }
+    out.write_all(b"\"")
+}
+
+/// Returns the size of a file node, or `None` for symlinks.
+fn size<T>(node: &FileNode<T>) -> Option<u64> {
+    match *node {
+        FileNode::Regular { size, .. } | FileNode::Directory { size, .. } => Some(size),
+        FileNode::Symlink { .. } => None,
+    }
+}
+
+/// Returns the target of a symlink, or `None` for other files.
+fn target<T>(node: &FileNode<T>) -> Option<&[u8]> {
+    match node {
+        FileNode::Symlink { target } => Some(target),
+        _ => None,
+    }
+}
+
+/// Returns the letter of a file type, as accepted by `nix-locate --type`.
+fn type_name(typ: FileType) -> String {
+    typ.to_possible_value()
+        .expect("every file type has a value")
+        .get_name()
+        .to_string()
+}
+
+/// The JSON object that describes a match.
+#[derive(Serialize)]
+struct JsonMatch<'a> {
+    /// The attribute, output, toplevel flag and system of the package.
+    #[serde(flatten)]
+    origin: Cow<'a, PathOrigin>,
+    store_path: Cow<'a, str>,
+    path: Bytes<'a>,
+    #[serde(rename = "type")]
+    typ: FileType,
+    size: Option<u64>,
+    target: Option<Bytes<'a>>,
+}
+
+impl<'a> JsonMatch<'a> {
+    fn new(store_path: &'a StorePath, entry: &'a FileTreeEntry) -> JsonMatch<'a> {
+        JsonMatch {
+            origin: store_path.origin(),
+            store_path: store_path.as_str(),
+            path: Bytes(&entry.path),
+            typ: entry.node.get_type(),
+            size: size(&entry.node),
+            target: target(&entry.node).map(Bytes),
+        }
+    }
+}
+
+/// Bytes that are serialized as a string if they are valid UTF-8, and as an array of bytes
+/// otherwise.
+///
+/// Unlike a lossy conversion to a string, this keeps paths that are not valid UTF-8 intact.
+pub struct Bytes<'a>(pub &'a [u8]);
+
+impl Serialize for Bytes<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match str::from_utf8(self.0) {
+            Ok(s) => serializer.serialize_str(s),
+            Err(_) => serializer.collect_seq(self.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_bytes::ByteBuf;
+
+    use super::*;
+    use crate::testing::store_path;
+
+    fn entries() -> [FileTreeEntry; 2] {
+        [
+            FileTreeEntry {
+                path: b"/bin/a,\"b\"".to_vec(),
+                node: FileNode::Regular {
+                    size: 10,
+                    executable: true,
+                },
+            },
+            FileTreeEntry {
+                path: b"/lib/\xff".to_vec(),
+                node: FileNode::Symlink {
+                    target: ByteBuf::from(b"../bin/\xfe".to_vec()),
+                },
+            },
+        ]
+    }
+
+    fn write(mut writer: Writer<Vec<u8>>) -> Vec<u8> {
+        let package = store_path("hello");
+        for entry in &entries() {
+            writer.write(&package, entry).expect("match can be written");
+        }
+        writer.finish().expect("output can be finished")
+    }
+
+    #[test]
+    fn test_json() {
+        let json = write(Writer::new(Vec::new(), Format::Json));
+        let json: serde_json::Value = serde_json::from_slice(&json).expect("valid JSON");
+        assert_eq!(json[0]["attr"], "hello");
+        assert_eq!(json[0]["path"], "/bin/a,\"b\"");
+        assert_eq!(json[0]["type"], "x");
+        assert_eq!(json[0]["size"], 10);
+        assert_eq!(json[0]["target"], serde_json::Value::Null);
+        assert_eq!(json[1]["path"], serde_json::json!(b"/lib/\xff"));
+        assert_eq!(json[1]["target"], serde_json::json!(b"../bin/\xfe"));
+        assert_eq!(json[1]["size"], serde_json::Value::Null);
+
+        let empty = Writer::new(Vec::new(), Format::Json)
+            .finish()
+            .expect("output can be finished");
+        assert_eq!(empty, b"[]\n");
+    }
+
+    #[test]
+    fn test_csv() {
+        let csv = write(Writer::new(Vec::new(), Format::Csv));
+        let store = "/nix/store/010yd8jls8w4vcnql4zhjbnyp2yay5pl-hello";
+        let expected = [
+            &b"attr,output,toplevel,system,store_path,path,type,size,target\n"[..],
+            b"hello,out,true,,",
+            store.as_bytes(),
+            b",\"/bin/a,\"\"b\"\"\",x,10,\n",
+            b"hello,out,true,,",
+            store.as_bytes(),
+            b",/lib/\xff,s,,../bin/\xfe\n",
+        ];
+        assert_eq!(csv, expected.concat());
+
+        let quote = |value: &[u8]| {
+            let mut out = Vec::new();
+            write_csv_value(&mut out, value).expect("value can be written");
+            out
+        };
+        assert_eq!(quote(b"plain"), b"plain");
+        assert_eq!(quote(b""), b"");
+        assert_eq!(quote(b"a,b"), b"\"a,b\"");
+        assert_eq!(quote(b"\"quoted\""), b"\"\"\"quoted\"\"\"");
+        assert_eq!(quote(b"line\nbreak"), b"\"line\nbreak\"");
+        assert_eq!(quote(b"cr\r"), b"\"cr\r\"");
+    }
+
+    #[test]
+    fn test_null_separated() {
+        let null = write(Writer::new(Vec::new(), Format::NullSeparated));
+        let store = "/nix/store/010yd8jls8w4vcnql4zhjbnyp2yay5pl-hello";
+        let expected = [store.as_bytes(), b"/lib/\xff"].concat();
+        assert_eq!(null.split(|b| *b == 0).nth(1), Some(&expected[..]));
+    }
+
+    #[test]
+    fn test_template() {
+        let template: Template = "{{{type}}} {path} -> {target}"
+            .parse()
+            .expect("valid template");
+        assert_eq!(
+            template.parts,
+            vec![
+                Part::Literal("{".to_string()),
+                Part::Field(Field::Type),
+                Part::Literal("} ".to_string()),
+                Part::Field(Field::Path),
+                Part::Literal(" -> ".to_string()),
+                Part::Field(Field::Target),
+            ]
+        );
+        let output = write(Writer::with_template(Vec::new(), template));
+        assert_eq!(
+            output,
+            b"{x} /bin/a,\"b\" -> \n{s} /lib/\xff -> ../bin/\xfe\n"
+        );
+    }
+
+    #[test]
+    fn test_template_errors() {
+        let parse = |s: &str| s.parse::<Template>().map(|_| ());
+        assert_eq!(
+            parse("{path"),
+            Err("unclosed '{' in template (write '{{' for a literal brace)".to_string())
+        );
+        assert_eq!(
+            parse("path}"),
+            Err("unmatched '}' in template (write '}}' for a literal brace)".to_string())
+        );
+        assert_eq!(
+            parse("{"),
+            Err("unclosed '{' in template (write '{{' for a literal brace)".to_string())
+        );
+        assert_eq!(
+            parse("}"),
+            Err("unmatched '}' in template (write '}}' for a literal brace)".to_string())
+        );
+        let unknown = parse("{unknown}").expect_err("unknown placeholder is rejected");
+        assert!(unknown.starts_with("unknown placeholder '{unknown}'"));
+        let empty = parse("{}").expect_err("empty placeholder is rejected");
+        assert!(empty.starts_with("unknown placeholder '{}'"));
+    }
+}
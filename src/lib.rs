@@ -6,14 +6,22 @@
     clippy::unwrap_used
 )]
 
+pub mod cache;
+pub mod daemon;
 pub mod database;
+pub mod diff;
 pub mod errors;
 pub mod files;
+pub mod format;
 pub mod frcode;
 pub mod hydra;
 pub mod listings;
+pub mod locate;
 pub mod nixpkgs;
 pub mod package;
+pub mod sqlite;
+#[cfg(test)]
+mod testing;
 pub mod util;
 pub mod workset;
 
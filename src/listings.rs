@@ -1,17 +1,20 @@
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io;
 use std::iter::FromIterator;
 
-use futures::{Stream, StreamExt, TryFutureExt};
+use futures::future::Either;
+use futures::{stream, Stream, StreamExt, TryFutureExt};
 use indexmap::map::Entry;
 use indexmap::IndexMap;
 use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
 
+use crate::database::Reader;
 use crate::errors::{Error, Result};
 use crate::files::FileTree;
 use crate::hydra::Fetcher;
 use crate::nixpkgs;
-use crate::package::StorePath;
+use crate::package::{PathOrigin, StorePath};
 use crate::workset::{WorkSet, WorkSetHandle, WorkSetWatch};
 
 /// A stream of store paths (packages) with their associated file listings.
@@ -22,23 +25,13 @@ pub trait FileListingStream: Stream<Item = Result<Option<(StorePath, String, Fil
 impl<T> FileListingStream for T where T: Stream<Item = Result<Option<(StorePath, String, FileTree)>>>
 {}
 
-/// Fetches all the file listings for the full closure of the given starting set of path.
-///
-/// This function will fetch the file listings of each path in the starting set. Additionally, it
-/// will also determine the references of each path and recursively fetch the file listings for those
-/// paths.
-///
-/// The `jobs` argument is used to specify how many requests should be done in parallel. No more than
-/// `jobs` requests will be in-flight at any given time.
-#[allow(clippy::result_large_err)]
-fn fetch_listings_impl(
-    fetcher: &Fetcher,
-    jobs: usize,
-    starting_set: Vec<StorePath>,
-) -> (impl FileListingStream + '_, WorkSetWatch) {
-    // Create the queue that will hold all the paths that still need processing.
-    // Initially, only the starting set needs processing.
+/// A store path with its NAR path and file listing, as yielded by a `FileListingStream`.
+type FileListing = (StorePath, String, FileTree);
 
+/// Deduplicates the starting set by the hash of the store paths.
+///
+/// If there are multiple paths with the same hash, the one with the shortest attribute path wins.
+fn dedup_starting_set(starting_set: Vec<StorePath>) -> IndexMap<String, StorePath> {
     // We can't use FromIterator here as we want shorter paths to win
     let mut map: IndexMap<String, StorePath> = IndexMap::with_capacity(starting_set.len());
 
@@ -56,24 +49,48 @@ fn fetch_listings_impl(
         };
     }
 
-    let workset = WorkSet::from_queue(map);
+    map
+}
 
-    // Processes a single store path, fetching the file listing for it and
-    // adding its references to the queue
-    let process = move |mut handle: WorkSetHandle<_, _>, path: StorePath| async move {
+/// Fetches all the file listings for the full closure of the given starting set of path.
+///
+/// This function will fetch the file listings of each path in the starting set. Additionally, it
+/// will also determine the references of each path and recursively fetch the file listings for those
+/// paths.
+///
+/// Paths whose hash is in `skip` are never fetched, even if they are referenced by another path.
+///
+/// The `jobs` argument is used to specify how many requests should be done in parallel. No more than
+/// `jobs` requests will be in-flight at any given time.
+///
+/// If `ordered` is true, the listings are returned in a deterministic order, which only depends on
+/// the starting set and not on the order in which requests complete. Since a dependency gets the
+/// origin of the path through which it was discovered first, this also makes the origins of all
+/// paths deterministic. This is slower, because a slow request delays all following paths.
+#[allow(clippy::result_large_err)]
+fn fetch_listings_impl(
+    fetcher: &Fetcher,
+    jobs: usize,
+    ordered: bool,
+    starting_set: IndexMap<String, StorePath>,
+    skip: HashSet<String>,
+) -> (impl FileListingStream + '_, WorkSetWatch) {
+    // Create the queue that will hold all the paths that still need processing.
+    // Initially, only the starting set needs processing.
+    let workset = WorkSet::from_queue(starting_set);
+    workset.mark_seen(skip);
+
+    // Processes a single store path, fetching the file listing and the references for it.
+    // The handle is passed on with the result, so that the references can be added to the queue.
+    let process = move |handle: WorkSetHandle<_, _>, path: StorePath| async move {
         let Some(parsed) = fetcher
             .fetch_references(path.clone())
             .map_err(|e| Error::FetchReferences { path, source: e })
             .await?
         else {
-            return Ok(None);
+            return Ok((handle, Vec::new(), None));
         };
 
-        for reference in parsed.references {
-            let hash = reference.hash().into_owned();
-            handle.add_work(hash, reference);
-        }
-
         let path = parsed.store_path.clone();
         let nar_path = parsed.nar_path;
 
@@ -82,16 +99,30 @@ fn fetch_listings_impl(
                 path: parsed.store_path,
                 source: e,
             }),
-            Ok(Some(files)) => Ok(Some((path, nar_path, files))),
-            Ok(None) => Ok(None),
+            Ok(Some(files)) => Ok((handle, parsed.references, Some((path, nar_path, files)))),
+            Ok(None) => Ok((handle, parsed.references, None)),
         }
     };
 
     // Process all paths in the queue, until the queue becomes empty.
     let watch = workset.watch();
-    let stream = workset
-        .map(move |(handle, path)| process(handle, path))
-        .buffer_unordered(jobs);
+    let results = workset.map(move |(handle, path)| process(handle, path));
+    let results = if ordered {
+        Either::Left(results.buffered(jobs))
+    } else {
+        Either::Right(results.buffer_unordered(jobs))
+    };
+
+    // Add the references to the queue. This happens in the order in which the results are
+    // returned, so the queue is deterministic if the results are.
+    let stream = results.map(|result| {
+        let (mut handle, references, listing) = result?;
+        for reference in references {
+            let hash = reference.hash().into_owned();
+            handle.add_work(hash, reference);
+        }
+        Ok(listing)
+    });
     (stream, watch)
 }
 
@@ -124,15 +155,14 @@ pub fn try_load_paths_cache() -> Result<Option<(impl FileListingStream, WorkSetW
     Ok(Some((stream, watch)))
 }
 
+/// Queries the store paths of all packages in nixpkgs for the given systems and scopes.
 #[allow(clippy::result_large_err)]
-pub fn fetch<'a>(
-    fetcher: &'a Fetcher,
-    jobs: usize,
+fn query_starting_set(
     nixpkgs: &str,
     systems: Vec<Option<&str>>,
     extra_scopes: &[String],
     show_trace: bool,
-) -> Result<(impl FileListingStream + 'a, WorkSetWatch)> {
+) -> Result<Vec<StorePath>> {
     let mut scopes = vec![None];
     scopes.extend(
         extra_scopes
@@ -151,13 +181,227 @@ pub fn fetch<'a>(
     }
 
     // Collect results in parallel.
-    let all_paths = all_queries
+    all_queries
         .par_iter()
         .flat_map_iter(|&(system, scope)| {
             nixpkgs::query_packages(nixpkgs, system, scope.as_deref(), show_trace)
         })
         .collect::<std::result::Result<_, nixpkgs::Error>>()
-        .map_err(|e| Error::QueryPackages { source: e })?;
+        .map_err(|e| Error::QueryPackages { source: e })
+}
+
+/// Fetches the file listings of all packages of nixpkgs and their dependencies.
+///
+/// See `fetch_listings_impl` for the meaning of `jobs` and `ordered`.
+#[allow(clippy::result_large_err)]
+pub fn fetch<'a>(
+    fetcher: &'a Fetcher,
+    jobs: usize,
+    ordered: bool,
+    nixpkgs: &str,
+    systems: Vec<Option<&str>>,
+    extra_scopes: &[String],
+    show_trace: bool,
+) -> Result<(impl FileListingStream + 'a, WorkSetWatch)> {
+    let all_paths = query_starting_set(nixpkgs, systems, extra_scopes, show_trace)?;
+    Ok(fetch_listings_impl(
+        fetcher,
+        jobs,
+        ordered,
+        dedup_starting_set(all_paths),
+        HashSet::new(),
+    ))
+}
+
+/// Like `fetch`, but reuses the file listings stored in a previous database where possible.
+///
+/// Top-level store paths of the previous database whose hash is still part of the starting set
+/// are taken from the previous database instead of being fetched from the binary cache. Since the
+/// closure of a store path cannot change without changing its hash, the same applies to all
+/// dependencies that were discovered through such a store path. Only the remaining paths are fetched.
+///
+/// Reused paths do not have a NAR path, so the NAR path of the returned listings is empty for them.
+#[allow(clippy::result_large_err, clippy::too_many_arguments)]
+pub fn fetch_incremental<'a>(
+    fetcher: &'a Fetcher,
+    jobs: usize,
+    ordered: bool,
+    nixpkgs: &str,
+    systems: Vec<Option<&str>>,
+    extra_scopes: &[String],
+    show_trace: bool,
+    previous: Reader,
+) -> Result<(impl FileListingStream + 'a, WorkSetWatch)> {
+    let mut starting_set = dedup_starting_set(query_starting_set(
+        nixpkgs,
+        systems,
+        extra_scopes,
+        show_trace,
+    )?);
+    let (skip, reused_listings) = reuse_listings(previous, &mut starting_set)?;
+    let (fetched, watch) = fetch_listings_impl(fetcher, jobs, ordered, starting_set, skip);
+    Ok((stream::iter(reused_listings).chain(fetched), watch))
+}
+
+/// Finds the store paths of the starting set whose file listings can be reused from a previous
+/// database (see `fetch_incremental`), and removes them from the starting set.
+///
+/// Returns the hashes of all reused paths, which must not be fetched, together with an iterator
+/// over their file listings (with the origins of the new starting set).
+#[allow(clippy::result_large_err)]
+fn reuse_listings(
+    previous: Reader,
+    starting_set: &mut IndexMap<String, StorePath>,
+) -> Result<(
+    HashSet<String>,
+    impl Iterator<Item = Result<Option<FileListing>>>,
+)> {
+    // The origin of a dependency refers to a top-level path that has the dependency in its closure.
+    // So a dependency can be reused if that top-level path is reused, and we identify top-level paths
+    // by their origin to find them.
+    type OriginKey = (String, String, Option<String>);
+    let origin_key = |origin: &PathOrigin| -> OriginKey {
+        (
+            origin.attr.clone(),
+            origin.output.clone(),
+            origin.system.clone(),
+        )
+    };
+
+    let mut reused_roots: HashMap<OriginKey, PathOrigin> = HashMap::new();
+    // New origins for all reused paths, indexed by hash.
+    let mut reused: HashMap<String, PathOrigin> = HashMap::new();
+    let mut dependencies = Vec::new();
+    for package in previous.packages() {
+        let (path, _) = package.map_err(|e| Error::ReadPreviousDatabase { source: e })?;
+        let origin = path.origin();
+        if !origin.toplevel {
+            dependencies.push((path.hash().into_owned(), origin_key(&origin)));
+        } else if let Some(new) = starting_set.swap_remove(path.hash().as_ref()) {
+            let new = new.origin().into_owned();
+            reused_roots.insert(origin_key(&origin), new.clone());
+            reused.insert(path.hash().into_owned(), new);
+        }
+    }
 
-    Ok(fetch_listings_impl(fetcher, jobs, all_paths))
+    for (hash, key) in dependencies {
+        if let Some(root) = reused_roots.get(&key) {
+            // the dependency may have become a top-level path itself
+            let origin = match starting_set.swap_remove(&hash) {
+                Some(path) => path.origin().into_owned(),
+                None => PathOrigin {
+                    toplevel: false,
+                    ..root.clone()
+                },
+            };
+            reused.insert(hash, origin);
+        }
+    }
+
+    let skip = reused.keys().cloned().collect();
+    let reused_listings = previous.packages().filter_map(move |package| {
+        let (path, entries) = match package {
+            Ok(v) => v,
+            Err(e) => return Some(Err(Error::ReadPreviousDatabase { source: e })),
+        };
+        let origin = reused.remove(path.hash().as_ref())?;
+        let path = StorePath::parse(origin, &path.as_str()).expect("valid store path");
+        Some(Ok(Some((
+            path,
+            String::new(),
+            FileTree::from_list(entries),
+        ))))
+    });
+    Ok((skip, reused_listings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{bin_tree, store_path_with_hash, TestDatabase};
+
+    #[test]
+    fn test_reuse_listings() {
+        let with_origin = |path: &StorePath, attr: &str, toplevel: bool| {
+            let origin = PathOrigin {
+                attr: attr.to_string(),
+                toplevel,
+                ..path.origin().into_owned()
+            };
+            StorePath::parse(origin, &path.as_str()).expect("valid store path")
+        };
+        let hello = store_path_with_hash("hello", "010yd8jls8w4vcnql4zhjbnyp2yay5pl");
+        let glibc = with_origin(
+            &store_path_with_hash("glibc", "7fzwp5z1nqy7rd6ygldrskm3dq6nhsmz"),
+            "hello",
+            false,
+        );
+        let zlib = with_origin(
+            &store_path_with_hash("zlib", "0c4zbwrq3gz1bwhs1bwqj7h4x2y3ksqw"),
+            "hello",
+            false,
+        );
+        let world = store_path_with_hash("world", "1c4zbwrq3gz1bwhs1bwqj7h4x2y3ksqw");
+        let previous = TestDatabase::new(
+            "reuse-listings",
+            &[
+                (hello.clone(), bin_tree(&["hello"])),
+                (glibc.clone(), bin_tree(&["ldd"])),
+                (zlib.clone(), bin_tree(&["minigzip"])),
+                (world.clone(), bin_tree(&["world"])),
+            ],
+        );
+
+        // `hello` is unchanged (but renamed), `world` was updated and `fresh` is new. `zlib`
+        // has become a top-level package
+        let updated_world = store_path_with_hash("world", "2c4zbwrq3gz1bwhs1bwqj7h4x2y3ksqw");
+        let fresh = store_path_with_hash("fresh", "3c4zbwrq3gz1bwhs1bwqj7h4x2y3ksqw");
+        let mut starting_set = dedup_starting_set(vec![
+            with_origin(&hello, "hello-renamed", true),
+            updated_world.clone(),
+            fresh.clone(),
+            with_origin(&zlib, "zlib", true),
+        ]);
+        let (skip, listings) =
+            reuse_listings(previous.reader(), &mut starting_set).expect("listings can be reused");
+
+        assert_eq!(
+            starting_set.into_values().collect::<Vec<_>>(),
+            vec![fresh, updated_world]
+        );
+        let mut skip = skip.into_iter().collect::<Vec<_>>();
+        skip.sort();
+        assert_eq!(
+            skip,
+            vec![
+                "010yd8jls8w4vcnql4zhjbnyp2yay5pl",
+                "0c4zbwrq3gz1bwhs1bwqj7h4x2y3ksqw",
+                "7fzwp5z1nqy7rd6ygldrskm3dq6nhsmz"
+            ]
+        );
+
+        let listings = listings
+            .map(|listing| {
+                let (path, nar_path, tree) = listing
+                    .expect("listing can be read")
+                    .expect("listing exists");
+                assert!(nar_path.is_empty());
+                (path, tree)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            listings,
+            vec![
+                (
+                    with_origin(&hello, "hello-renamed", true),
+                    bin_tree(&["hello"])
+                ),
+                (
+                    with_origin(&glibc, "hello-renamed", false),
+                    bin_tree(&["ldd"])
+                ),
+                (with_origin(&zlib, "zlib", true), bin_tree(&["minigzip"])),
+            ]
+        );
+    }
 }
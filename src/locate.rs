@@ -0,0 +1,823 @@
+//! The search options of nix-locate.
+//!
+//! A `Request` describes a search with the same options as the command line of nix-locate.
+//! It is shared by nix-locate and its daemon (see the `daemon` module), so that a search gives
+//! the same results no matter where it runs.
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::str;
+
+use regex::bytes::Regex;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::database::{self, Reader, Resolver};
+use crate::files::{FileNode, FileTreeEntry, FileType, Resolved};
+use crate::package::{PathOrigin, StorePath};
+use crate::util;
+
+/// A search for files in the database.
+///
+/// All fields are optional when deserializing a request, and default to the defaults of the
+/// corresponding command line options of nix-locate.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Request {
+    /// The pattern for which to search.
+    pub pattern: String,
+    /// Treat `pattern`, `package`, `attr`, `output` and `target` as regexes instead of literal
+    /// text.
+    pub regex: bool,
+    /// Only match files whose basename matches `pattern` exactly.
+    pub whole_name: bool,
+    /// Only match `pattern` at the root of a package.
+    pub at_root: bool,
+    /// Only match files of packages whose name matches this pattern.
+    pub package: Option<String>,
+    /// Only match files of packages whose attribute matches this pattern. With `whole_name`,
+    /// the pattern has to match the whole attribute.
+    pub attr: Option<String>,
+    /// Only match files of packages whose output matches this pattern. With `whole_name`, the
+    /// pattern has to match the whole name of the output.
+    pub output: Option<String>,
+    /// Only match files of the package with this hash.
+    pub hash: Option<String>,
+    /// Only match files of packages for this system. If this is not set and the database
+    /// contains packages for more than one system, only packages for the current system match.
+    pub system: Option<String>,
+    /// Match files of packages for all systems, instead of only the current system.
+    pub all_systems: bool,
+    /// Only match regular files that are at least this large (in bytes).
+    pub min_size: Option<u64>,
+    /// Only match regular files that are at most this large (in bytes).
+    pub max_size: Option<u64>,
+    /// Only match symlinks whose target matches this pattern.
+    pub target: Option<String>,
+    /// Only match files that have one of these types. All types match if this is empty.
+    pub types: Vec<FileType>,
+    /// Also match files of packages that are not top-level packages.
+    pub all: bool,
+    /// Return all matches, instead of only the paths where `pattern` matches some part of
+    /// the last component of the path.
+    pub no_group: bool,
+}
+
+impl Request {
+    /// Returns the system whose packages match in the given database, or `None` if packages
+    /// of all systems match.
+    pub fn system_for(&self, reader: &Reader) -> Option<String> {
+        match &self.system {
+            Some(system) => Some(system.clone()),
+            None if !self.all_systems && reader.metadata().is_some_and(|m| m.systems.len() > 1) => {
+                Some(util::current_system())
+            }
+            None => None,
+        }
+    }
+}
+
+/// The error returned if a pattern of a request is not a valid regex.
+#[derive(Error, Debug)]
+#[error("constructing the regular expression from the pattern '{pattern}' failed: {source}")]
+pub struct PatternError {
+    pub pattern: String,
+    #[source]
+    pub source: regex::Error,
+}
+
+/// A request whose patterns have been compiled, so that it can search a database.
+pub struct Search {
+    request: Request,
+    /// The regex that file paths have to match.
+    pattern: Regex,
+    /// The regex that package names have to match, if any.
+    package_pattern: Option<Regex>,
+    /// The regex that attributes have to match, if any.
+    attr_pattern: Option<Regex>,
+    /// The regex that outputs have to match, if any.
+    output_pattern: Option<Regex>,
+    /// The regex that symlink targets have to match, if any.
+    target_pattern: Option<Regex>,
+}
+
+impl Search {
+    /// Compiles the patterns of the request.
+    pub fn new(request: Request) -> Result<Search, PatternError> {
+        // `wrap` applies --at-root and --whole-name to the path pattern, while `whole` makes
+        // other patterns match whole strings
+        let make_regex = |s: &str, wrap: bool, whole: bool| {
+            let mut regex = if request.regex {
+                s.to_string()
+            } else {
+                regex::escape(s)
+            };
+            if wrap && request.at_root {
+                regex.insert(0, '^');
+            }
+            if wrap && request.whole_name {
+                regex.push('$');
+            }
+            if whole {
+                regex = format!("^(?:{})$", regex);
+            }
+            Regex::new(&regex).map_err(|e| PatternError {
+                pattern: regex,
+                source: e,
+            })
+        };
+
+        let pattern = make_regex(&request.pattern, true, false)?;
+        let optional = |s: &Option<String>, whole: bool| {
+            s.as_deref()
+                .map(|s| make_regex(s, false, whole))
+                .transpose()
+        };
+        let package_pattern = optional(&request.package, false)?;
+        // like the path pattern, attributes and outputs only need to match as a whole with
+        // --whole-name (so that `dev` does not match `devdoc`)
+        let attr_pattern = optional(&request.attr, request.whole_name)?;
+        let output_pattern = optional(&request.output, request.whole_name)?;
+        let target_pattern = optional(&request.target, false)?;
+        Ok(Search {
+            request,
+            pattern,
+            package_pattern,
+            attr_pattern,
+            output_pattern,
+            target_pattern,
+        })
+    }
+
+    /// Returns the request from which this search was created.
+    pub fn request(&self) -> &Request {
+        &self.request
+    }
+
+    /// Returns the regex that file paths have to match, for example to highlight the matched
+    /// part of a path.
+    pub fn pattern(&self) -> &Regex {
+        &self.pattern
+    }
+
+    /// Searches the database, returning an iterator over all matching files together with the
+    /// store paths of their packages.
+    ///
+    /// The matches are returned in database order. Use `SortKey` to sort them differently.
+    ///
+    /// If executables are requested, symlinks that resolve to executables also match. The
+    /// symlinks are resolved in batches of `RESOLVE_BATCH` while the matches are returned.
+    pub fn run(&self, reader: Reader) -> Result<Matches<'_>, database::Error> {
+        let system = self.request.system_for(&reader);
+        let results = reader
+            .clone()
+            .query(&self.pattern)
+            .package_pattern(self.package_pattern.as_ref())
+            .attr_pattern(self.attr_pattern.as_ref())
+            .output_pattern(self.output_pattern.as_ref())
+            .hash(self.request.hash.clone())
+            .system(system)
+            .min_size(self.request.min_size)
+            .max_size(self.request.max_size)
+            .target_pattern(self.target_pattern.as_ref())
+            .run()?;
+        let results = results.filter(move |v| {
+            v.as_ref()
+                .map_or(true, |(store_path, entry)| self.matches(store_path, entry))
+        });
+        if !self.resolves_symlinks() {
+            return Ok(Box::new(results));
+        }
+        Ok(Box::new(ResolveSymlinks {
+            matches: results,
+            reader,
+            resolver: None,
+            ready: VecDeque::new(),
+            done: false,
+        }))
+    }
+
+    /// Returns whether symlinks have to be resolved to find out if they match the requested
+    /// types, which is the case if executables are requested but symlinks are not.
+    fn resolves_symlinks(&self) -> bool {
+        let types = &self.request.types;
+        types.contains(&FileType::Regular { executable: true })
+            && !types.contains(&FileType::Symlink)
+    }
+
+    /// Checks the conditions of the request that are not already checked by the database query.
+    fn matches(&self, store_path: &StorePath, entry: &FileTreeEntry) -> bool {
+        let request = &self.request;
+        let m = self
+            .pattern
+            .find_iter(&entry.path)
+            .last()
+            .expect("path should match the pattern");
+
+        let conditions = [
+            request.no_group || !entry.path[m.end()..].contains(&b'/'),
+            request.all || store_path.origin().toplevel,
+            request.types.is_empty()
+                || request.types.contains(&entry.node.get_type())
+                || (self.resolves_symlinks() && entry.node.get_type() == FileType::Symlink),
+        ];
+        conditions.iter().all(|c| *c)
+    }
+}
+
+/// The number of symlinks that are resolved together when searching for executables.
+const RESOLVE_BATCH: usize = 256;
+
+/// An iterator over matches that removes the symlinks that do not resolve to an executable,
+/// for example because they are dangling. Symlinks whose target is outside the database cannot
+/// be resolved and are removed as well.
+///
+/// Matches are passed on in order, so matches after a symlink are held back until the symlink
+/// is resolved. To resolve many symlinks at once, up to `RESOLVE_BATCH` symlinks are collected
+/// before they are resolved.
+struct ResolveSymlinks<I> {
+    matches: I,
+    reader: Reader,
+    /// The resolver, which keeps the store paths it has read for later batches. It is created
+    /// with the first symlink, since that tells us the store directory.
+    resolver: Option<Resolver>,
+    /// The matches whose symlinks have been resolved, but that were not returned yet.
+    ready: VecDeque<Result<(StorePath, FileTreeEntry), database::Error>>,
+    /// Set when there are no more matches, or resolving failed.
+    done: bool,
+}
+
+impl<I> ResolveSymlinks<I>
+where
+    I: Iterator<Item = Result<(StorePath, FileTreeEntry), database::Error>>,
+{
+    /// Reads the next batch of matches and resolves its symlinks, adding the matches to
+    /// `ready`.
+    fn fill(&mut self) {
+        let is_symlink = |entry: &FileTreeEntry| matches!(entry.node, FileNode::Symlink { .. });
+        let mut batch = Vec::new();
+        let mut error = None;
+        let mut symlinks = 0;
+        while symlinks < RESOLVE_BATCH {
+            match self.matches.next() {
+                Some(Ok(m)) if is_symlink(&m.1) => {
+                    symlinks += 1;
+                    batch.push(m);
+                }
+                Some(Ok(m)) => {
+                    batch.push(m);
+                    // without symlinks to wait for, matches can be returned right away
+                    if symlinks == 0 {
+                        break;
+                    }
+                }
+                Some(Err(e)) => {
+                    // an error ends the matches, after the matches before it
+                    self.done = true;
+                    error = Some(Err(e));
+                    break;
+                }
+                None => {
+                    self.done = true;
+                    break;
+                }
+            }
+        }
+
+        let paths = batch
+            .iter()
+            .filter(|(_, entry)| is_symlink(entry))
+            .map(|(store_path, entry)| [store_path.as_str().as_bytes(), &entry.path].concat())
+            .collect::<Vec<_>>();
+        let mut resolved = Vec::new().into_iter();
+        if let Some((store_path, _)) = batch.iter().find(|(_, entry)| is_symlink(entry)) {
+            let reader = &self.reader;
+            let resolver = self
+                .resolver
+                .get_or_insert_with(|| Resolver::new(reader.clone(), &store_path.store_dir()));
+            match resolver.resolve_all(paths) {
+                Ok(results) => resolved = results.into_iter(),
+                Err(e) => {
+                    self.done = true;
+                    self.ready.push_back(Err(e));
+                    return;
+                }
+            }
+        }
+
+        self.ready.extend(
+            batch
+                .into_iter()
+                .filter(|(_, entry)| {
+                    !is_symlink(entry)
+                        || matches!(
+                            resolved.next(),
+                            Some(Resolved::Found(FileNode::Regular {
+                                executable: true,
+                                ..
+                            }))
+                        )
+                })
+                .map(Ok),
+        );
+        self.ready.extend(error);
+    }
+}
+
+impl<I> Iterator for ResolveSymlinks<I>
+where
+    I: Iterator<Item = Result<(StorePath, FileTreeEntry), database::Error>>,
+{
+    type Item = Result<(StorePath, FileTreeEntry), database::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(result) = self.ready.pop_front() {
+                return Some(result);
+            }
+            if self.done {
+                return None;
+            }
+            self.fill();
+        }
+    }
+}
+
+/// The package that provides a file in the store, as found by `find_owner`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Owner {
+    /// The store path of the package, with the origin recorded in the database.
+    pub store_path: StorePath,
+    /// The path of the file inside the store path, such as `/bin/hello`. This is empty if the
+    /// path is the store path itself.
+    pub path: Vec<u8>,
+    /// The file, or `None` if the file listing of the package does not contain it.
+    pub node: Option<FileNode<()>>,
+}
+
+/// Finds the package in the database that provides a file in the store, like `dpkg -S`.
+///
+/// `path` is an absolute path into the store, such as `/nix/store/<hash>-hello/bin/hello`.
+/// Returns `None` if the path is not in the store or its store path is not in the database.
+/// Symlinks are not followed, so the owner of a symlink is the package containing the symlink.
+pub fn find_owner(reader: &Reader, path: &[u8]) -> Result<Option<Owner>, database::Error> {
+    let Some((store_path, path)) = split_store_path(path) else {
+        return Ok(None);
+    };
+    // the origin is not known until the package is found in the database
+    let origin = PathOrigin {
+        attr: String::new(),
+        output: String::new(),
+        toplevel: false,
+        system: None,
+    };
+    let Some(store_path) = StorePath::parse(origin, store_path) else {
+        return Ok(None);
+    };
+    let packages = reader.package_entries(|found| found.hash() == store_path.hash())?;
+    Ok(packages.into_iter().next().map(|(store_path, entries)| {
+        let node = entries
+            .into_iter()
+            .find(|entry| entry.path == path)
+            .map(|entry| entry.node);
+        Owner {
+            store_path,
+            path: path.to_vec(),
+            node,
+        }
+    }))
+}
+
+/// Splits an absolute path into the store path containing it and the path inside that store
+/// path, without trailing slashes.
+///
+/// The store path is the first component of the form `<hash>-<name>`, where the hash consists
+/// of 32 lowercase letters and digits. Everything before it is the store directory.
+fn split_store_path(path: &[u8]) -> Option<(&str, &[u8])> {
+    if !path.starts_with(b"/") {
+        return None;
+    }
+    let mut start = 0;
+    for component in path.split(|c| *c == b'/') {
+        let end = start + component.len();
+        let is_hash = |hash: &[u8]| {
+            hash.iter()
+                .all(|c| c.is_ascii_digit() || c.is_ascii_lowercase())
+        };
+        if component.get(32) == Some(&b'-') && is_hash(&component[..32]) {
+            let rest = &path[end..];
+            let rest_len = rest.iter().rposition(|c| *c != b'/').map_or(0, |i| i + 1);
+            return Some((str::from_utf8(&path[..end]).ok()?, &rest[..rest_len]));
+        }
+        start = end + 1;
+    }
+    None
+}
+
+/// An iterator over the matches of a search, created by `Search::run`.
+pub type Matches<'a> =
+    Box<dyn Iterator<Item = Result<(StorePath, FileTreeEntry), database::Error>> + 'a>;
+
+/// A key by which the matches of a search can be sorted.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortKey {
+    /// The attribute and output of the package.
+    Attr,
+    /// The path of the file inside the package.
+    Path,
+    /// The size of the file (symlinks have no size and come first).
+    Size,
+    /// The store path of the package.
+    StorePath,
+}
+
+impl SortKey {
+    /// Compares two matches by this key.
+    pub fn compare(
+        self,
+        a: &(StorePath, FileTreeEntry),
+        b: &(StorePath, FileTreeEntry),
+    ) -> Ordering {
+        let size = |entry: &FileTreeEntry| match entry.node {
+            FileNode::Regular { size, .. } | FileNode::Directory { size, .. } => Some(size),
+            FileNode::Symlink { .. } => None,
+        };
+        match self {
+            SortKey::Attr => {
+                let (a, b) = (a.0.origin(), b.0.origin());
+                (&a.attr, &a.output).cmp(&(&b.attr, &b.output))
+            }
+            SortKey::Path => a.1.path.cmp(&b.1.path),
+            SortKey::Size => size(&a.1).cmp(&size(&b.1)),
+            SortKey::StorePath => a.0.as_str().cmp(&b.0.as_str()),
+        }
+    }
+
+    /// Sorts matches by this key, in descending order if `reverse` is set.
+    ///
+    /// The sort is stable: matches with the same key stay in the order in which they were
+    /// found (which is the order of the database), even when sorting in descending order.
+    pub fn sort(self, matches: &mut [(StorePath, FileTreeEntry)], reverse: bool) {
+        if reverse {
+            matches.sort_by(|a, b| self.compare(b, a));
+        } else {
+            matches.sort_by(|a, b| self.compare(a, b));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{
+        bin_tree, store_path, store_path_for_system, symlink_packages, TestDatabase,
+    };
+
+    /// Returns `name:path` for each match, to compare matches in assertions.
+    fn names(matches: &[(StorePath, FileTreeEntry)]) -> Vec<String> {
+        matches
+            .iter()
+            .map(|(store_path, entry)| {
+                format!(
+                    "{}:{}",
+                    store_path.name(),
+                    String::from_utf8_lossy(&entry.path)
+                )
+            })
+            .collect()
+    }
+
+    /// Runs the search of a request on the database.
+    fn search(reader: &Reader, request: Request) -> Vec<(StorePath, FileTreeEntry)> {
+        Search::new(request)
+            .expect("valid request")
+            .run(reader.clone())
+            .expect("search can be run")
+            .collect::<Result<_, _>>()
+            .expect("entries can be read")
+    }
+
+    #[test]
+    fn test_result_order() {
+        let packages = [
+            (store_path("b"), bin_tree(&["one", "two", "three"])),
+            (store_path("a"), bin_tree(&["four", "five"])),
+            (store_path("c"), bin_tree(&["six"])),
+        ];
+        let database = TestDatabase::new("result-order", &packages);
+
+        // without sorting, the matches of each package come in the order of the database
+        let mut results = search(
+            &database.reader(),
+            Request {
+                pattern: "/bin/".to_string(),
+                no_group: true,
+                ..Request::default()
+            },
+        );
+        let expected = packages
+            .iter()
+            .flat_map(|(store_path, tree)| {
+                tree.to_list(b"")
+                    .into_iter()
+                    .filter(|entry| entry.path.starts_with(b"/bin/"))
+                    .map(move |entry| (store_path.clone(), entry))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(names(&results), names(&expected));
+
+        SortKey::Path.sort(&mut results, false);
+        assert_eq!(
+            names(&results),
+            [
+                "a:/bin/five",
+                "a:/bin/four",
+                "b:/bin/one",
+                "c:/bin/six",
+                "b:/bin/three",
+                "b:/bin/two"
+            ]
+        );
+
+        // the sort is stable, also in reverse
+        SortKey::Attr.sort(&mut results, true);
+        assert_eq!(
+            names(&results),
+            [
+                "c:/bin/six",
+                "b:/bin/one",
+                "b:/bin/three",
+                "b:/bin/two",
+                "a:/bin/five",
+                "a:/bin/four"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_system() {
+        let current =
+            store_path_for_system(&util::current_system(), "010yd8jls8w4vcnql4zhjbnyp2yay5pl");
+        let other = store_path_for_system("other-system", "7fzwp5z1nqy7rd6ygldrskm3dq6nhsmz");
+        let database = TestDatabase::new(
+            "locate-system",
+            &[
+                (current.clone(), bin_tree(&["hello"])),
+                (other.clone(), bin_tree(&["hello"])),
+            ],
+        );
+        let reader = database.reader();
+
+        // the database contains more than one system, so searches default to the current one
+        let packages = |request: Request| {
+            let request = Request {
+                pattern: "hello".to_string(),
+                ..request
+            };
+            search(&reader, request)
+                .into_iter()
+                .map(|(store_path, _)| store_path)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(packages(Request::default()), vec![current.clone()]);
+        assert_eq!(
+            packages(Request {
+                all_systems: true,
+                ..Request::default()
+            }),
+            vec![current, other.clone()]
+        );
+        assert_eq!(
+            packages(Request {
+                system: Some("other-system".to_string()),
+                ..Request::default()
+            }),
+            vec![other]
+        );
+
+        // databases without packages for several systems are searched completely
+        let single = TestDatabase::new(
+            "locate-single-system",
+            &[(store_path("hello"), bin_tree(&["hello"]))],
+        );
+        assert_eq!(Request::default().system_for(&single.reader()), None);
+    }
+
+    #[test]
+    fn test_owner() {
+        let database = TestDatabase::new("owner", &[(store_path("hello"), bin_tree(&["hello"]))]);
+        let reader = database.reader();
+        let owner = |path: &str| {
+            find_owner(&reader, path.as_bytes())
+                .expect("owner can be looked up")
+                .map(|owner| (owner.store_path, owner.path, owner.node))
+        };
+        let root = store_path("hello").as_str().into_owned();
+        assert_eq!(
+            owner(&format!("{}/bin/hello", root)),
+            Some((
+                store_path("hello"),
+                b"/bin/hello".to_vec(),
+                Some(FileNode::Regular {
+                    size: 10,
+                    executable: true
+                })
+            ))
+        );
+        assert_eq!(
+            owner(&format!("{}/bin/", root)).and_then(|o| o.2),
+            Some(FileNode::Directory {
+                size: 1,
+                contents: ()
+            })
+        );
+        assert_eq!(owner(&root).map(|o| o.1), Some(Vec::new()));
+        assert_eq!(
+            owner(&format!("{}/bin/missing", root)),
+            Some((store_path("hello"), b"/bin/missing".to_vec(), None))
+        );
+        assert_eq!(
+            owner("/nix/store/7fzwp5z1nqy7rd6ygldrskm3dq6nhsmz-other/bin/hello"),
+            None
+        );
+        assert_eq!(owner("/usr/bin/hello"), None);
+    }
+
+    #[test]
+    fn test_attr_output() {
+        let package = |attr: &str, output: &str, hash: &str| {
+            StorePath::parse(
+                PathOrigin {
+                    attr: attr.to_string(),
+                    output: output.to_string(),
+                    toplevel: true,
+                    system: None,
+                },
+                &format!("/nix/store/{}-{}", hash, attr),
+            )
+            .expect("valid store path")
+        };
+        let packages = [
+            package(
+                "python3Packages.numpy",
+                "out",
+                "010yd8jls8w4vcnql4zhjbnyp2yay5pl",
+            ),
+            package(
+                "python3Packages.numpy",
+                "dev",
+                "7fzwp5z1nqy7rd6ygldrskm3dq6nhsmz",
+            ),
+            package(
+                "python3Packages.numpy",
+                "devdoc",
+                "0c4zbwrq3gz1bwhs1bwqj7h4x2y3ksqw",
+            ),
+            package("numpy", "dev", "1c4zbwrq3gz1bwhs1bwqj7h4x2y3ksqw"),
+            package(
+                "python3Packages.numpy-stl",
+                "out",
+                "2c4zbwrq3gz1bwhs1bwqj7h4x2y3ksqw",
+            ),
+        ]
+        .map(|store_path| (store_path, bin_tree(&["f2py"])));
+        let database = TestDatabase::new("locate-attr-output", &packages);
+        let reader = database.reader();
+
+        // attributes and outputs match like the path pattern: any part matches, unless the
+        // whole name is requested
+        let count = |request: Request| {
+            search(
+                &reader,
+                Request {
+                    pattern: "f2py".to_string(),
+                    ..request
+                },
+            )
+            .len()
+        };
+        let filter = |attr: Option<&str>, output: Option<&str>| Request {
+            attr: attr.map(str::to_string),
+            output: output.map(str::to_string),
+            ..Request::default()
+        };
+        assert_eq!(count(filter(Some("numpy"), None)), 5);
+        assert_eq!(count(filter(Some("python3Packages."), None)), 4);
+        assert_eq!(count(filter(None, Some("dev"))), 3);
+        assert_eq!(count(filter(None, Some("de"))), 3);
+        assert_eq!(count(filter(Some("python3Packages."), Some("dev"))), 2);
+        assert_eq!(
+            count(Request {
+                whole_name: true,
+                ..filter(None, Some("dev"))
+            }),
+            2
+        );
+        assert_eq!(
+            count(Request {
+                whole_name: true,
+                ..filter(None, Some("de"))
+            }),
+            0
+        );
+        assert_eq!(
+            count(Request {
+                whole_name: true,
+                ..filter(Some("python3Packages.numpy"), None)
+            }),
+            3
+        );
+        assert_eq!(
+            count(Request {
+                whole_name: true,
+                ..filter(Some("python3Packages."), Some("dev"))
+            }),
+            0
+        );
+        assert_eq!(
+            count(Request {
+                regex: true,
+                ..filter(Some("^python3Packages\\."), Some("^dev$"))
+            }),
+            1
+        );
+    }
+
+    #[test]
+    fn test_resolve_symlinks() {
+        let database = TestDatabase::new("locate-resolve", &symlink_packages());
+
+        // symlinks to executables are found with `--type x`, dangling ones are not
+        let request = Request {
+            pattern: "/bin/".to_string(),
+            types: vec![FileType::Regular { executable: true }],
+            ..Request::default()
+        };
+        let mut found = names(&search(&database.reader(), request));
+        found.sort();
+        assert_eq!(
+            found,
+            vec![
+                "hello:/bin/hello",
+                "hello:/bin/relative",
+                "wrapper:/bin/absolute",
+                "wrapper:/bin/hello",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve_symlinks_streaming() {
+        let packages = symlink_packages();
+        let database = TestDatabase::new("locate-resolve-streaming", &packages);
+        let entry = |path: &str, node: FileNode<()>| FileTreeEntry {
+            path: path.as_bytes().to_vec(),
+            node,
+        };
+        let regular = FileNode::Regular {
+            size: 10,
+            executable: true,
+        };
+        let symlink = |target: &str| FileNode::Symlink {
+            target: serde_bytes::ByteBuf::from(target),
+        };
+        let hello = packages[0].0.clone();
+        let matches = || {
+            [
+                Ok((hello.clone(), entry("/bin/hello", regular.clone()))),
+                Ok((hello.clone(), entry("/bin/dangling", symlink("missing")))),
+                Ok((
+                    hello.clone(),
+                    entry("/bin/relative", symlink("../libexec/hello")),
+                )),
+                Err(database::Error::MissingPackageEntry),
+            ]
+            .into_iter()
+        };
+        fn resolve<I>(reader: Reader, matches: I) -> ResolveSymlinks<I> {
+            ResolveSymlinks {
+                matches,
+                reader,
+                resolver: None,
+                ready: VecDeque::new(),
+                done: false,
+            }
+        }
+
+        // symlinks are resolved in order, and errors come after the matches before them
+        let results = resolve(database.reader(), matches())
+            .map(|r| r.map(|(_, entry)| String::from_utf8_lossy(&entry.path).into_owned()))
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            &results[..],
+            [Ok(a), Ok(b), Err(database::Error::MissingPackageEntry)]
+                if a == "/bin/hello" && b == "/bin/relative"
+        ));
+
+        // matches before the first symlink are returned without reading further
+        let mut pulled = 0;
+        let mut results = resolve(database.reader(), matches().inspect(|_| pulled += 1));
+        assert!(results.next().is_some_and(|r| r.is_ok()));
+        drop(results);
+        assert_eq!(pulled, 1);
+    }
+}
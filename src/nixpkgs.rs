@@ -67,6 +67,36 @@ pub fn query_packages(
     }
 }
 
+/// Calls `nix-instantiate` to determine the version of the given nixpkgs (the value of `lib.version`).
+///
+/// The `nixpkgs` argument accepts the same values as for `query_packages`.
+///
+/// Returns `None` if the version could not be determined, for example because the expression
+/// is not a nixpkgs checkout or failed to evaluate.
+pub fn query_version(nixpkgs: &str) -> Option<String> {
+    let output = Command::new("nix-instantiate")
+        .arg("--eval")
+        .arg("--json")
+        .arg("--arg")
+        .arg("config")
+        .arg("{ }")
+        .arg("--arg")
+        .arg("overlays")
+        .arg("[ ]")
+        .arg("--attr")
+        .arg("lib.version")
+        .arg(nixpkgs)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+    serde_json::from_slice(&output.stdout).ok()
+}
+
 /// An iterator that parses the output of nix-env and returns parsed store paths.
 ///
 /// Use `query_packages` to create a value of this type.
@@ -377,15 +407,13 @@ impl<R: Read> PackagesParser<R> {
                     }
                 }
 
-                EndElement { name: element_name } => {
-                    if element_name.local_name == "item" {
-                        if self.current_item.is_none() {
-                            return Err(self.err(MissingStartTag {
-                                element_name: "item".into(),
-                            }));
-                        }
-                        self.current_item = None
+                EndElement { name: element_name } if element_name.local_name == "item" => {
+                    if self.current_item.is_none() {
+                        return Err(self.err(MissingStartTag {
+                            element_name: "item".into(),
+                        }));
                     }
+                    self.current_item = None
                 }
 
                 EndDocument => break,
@@ -0,0 +1,165 @@
+//! Exporting a database to SQLite.
+//!
+//! The nix-index database format is optimized for searching file paths, but not for other
+//! kinds of queries. This module writes the complete contents of a database to a normalized
+//! SQLite schema, so that it can be analyzed with SQL:
+//!
+//! ```sql
+//! create table Packages (
+//!     id          integer primary key,
+//!     store_path  text not null,
+//!     hash        text not null,
+//!     name        text not null,
+//!     attr        text not null,
+//!     output      text not null,
+//!     toplevel    integer not null,
+//!     system      text
+//! );
+//!
+//! create table Files (
+//!     package     integer not null references Packages(id),
+//!     path        text not null,
+//!     type        text not null,
+//!     size        integer,
+//!     target      text,
+//!     primary key (package, path)
+//! ) without rowid;
+//! ```
+//!
+//! The `type` of a file is one of `r` (regular file), `x` (executable), `d` (directory) or
+//! `s` (symlink), like in the output of nix-locate. The `size` of a directory is the number of
+//! its entries, symlinks have no size but a `target` instead. Paths and symlink targets that are
+//! not valid UTF-8 are stored as blobs.
+use std::str;
+
+use rusqlite::types::{ToSqlOutput, ValueRef};
+use rusqlite::Connection;
+use thiserror::Error;
+
+use crate::database::{self, Reader};
+use crate::files::{FileNode, FileTreeEntry};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("reading the database failed: {0}")]
+    Database(#[from] database::Error),
+    #[error("writing to SQLite failed: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+}
+
+const SCHEMA: &str = r#"
+    create table Packages (
+        id          integer primary key,
+        store_path  text not null,
+        hash        text not null,
+        name        text not null,
+        attr        text not null,
+        output      text not null,
+        toplevel    integer not null,
+        system      text
+    );
+
+    create table Files (
+        package     integer not null references Packages(id),
+        path        text not null,
+        type        text not null,
+        size        integer,
+        target      text,
+        primary key (package, path)
+    ) without rowid;
+"#;
+
+/// Indices that are created after all rows have been inserted, which is faster than updating
+/// them for every row.
+const INDICES: &str = r#"
+    create index PackagesByHash on Packages(hash);
+    create index PackagesByAttr on Packages(attr, output);
+    create index FilesByPath on Files(path);
+"#;
+
+/// Returns a byte string as SQLite text if it is valid UTF-8, and as a blob otherwise.
+fn bytes_value(bytes: &[u8]) -> ToSqlOutput<'_> {
+    match str::from_utf8(bytes) {
+        Ok(_) => ToSqlOutput::Borrowed(ValueRef::Text(bytes)),
+        Err(_) => ToSqlOutput::Borrowed(ValueRef::Blob(bytes)),
+    }
+}
+
+/// Writes all packages and file entries of the database to the given SQLite connection,
+/// which should refer to an empty database.
+///
+/// Returns the number of packages and the number of file entries that were written.
+pub fn export(reader: &Reader, connection: &mut Connection) -> Result<(u64, u64), Error> {
+    let transaction = connection.transaction()?;
+    transaction.execute_batch(SCHEMA)?;
+
+    let (mut packages, mut entries) = (0, 0);
+    {
+        let mut insert_package = transaction.prepare(
+            "insert into Packages(store_path, hash, name, attr, output, toplevel, system) values (?, ?, ?, ?, ?, ?, ?)",
+        )?;
+        let mut insert_file = transaction.prepare(
+            "insert into Files(package, path, type, size, target) values (?, ?, ?, ?, ?)",
+        )?;
+
+        for package in reader.packages() {
+            let (path, files) = package?;
+            let origin = path.origin();
+            let id = insert_package.insert((
+                path.as_str(),
+                path.hash(),
+                path.name(),
+                &origin.attr,
+                &origin.output,
+                origin.toplevel,
+                &origin.system,
+            ))?;
+            packages += 1;
+
+            for FileTreeEntry { path, node } in files {
+                // sizes of files in the nix store always fit into SQLite's signed integers
+                let (typ, size, target) = match node {
+                    FileNode::Regular { size, executable } => {
+                        (if executable { "x" } else { "r" }, Some(size as i64), None)
+                    }
+                    FileNode::Directory { size, .. } => ("d", Some(size as i64), None),
+                    FileNode::Symlink { ref target } => ("s", None, Some(bytes_value(target))),
+                };
+                insert_file.execute((id, bytes_value(&path), typ, size, target))?;
+                entries += 1;
+            }
+        }
+    }
+
+    transaction.execute_batch(INDICES)?;
+    transaction.commit()?;
+    Ok((packages, entries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{bin_tree, store_path, TestDatabase};
+
+    #[test]
+    fn test_export() {
+        let database = TestDatabase::new(
+            "export-sqlite",
+            &[(store_path("both"), bin_tree(&["hello", "world"]))],
+        );
+
+        let mut connection = Connection::open_in_memory().expect("connection opens");
+        assert_eq!(
+            export(&database.reader(), &mut connection).expect("export succeeds"),
+            (1, 4)
+        );
+        let row: (String, String, i64) = connection
+            .query_row(
+                "select attr, type, size from Files join Packages on id = package where path = ?",
+                ["/bin/world"],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .expect("file is exported");
+        assert_eq!(row, ("both".to_string(), "x".to_string(), 10));
+    }
+}
@@ -0,0 +1,191 @@
+//! Fixtures that are shared by the tests of the modules of this crate.
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::{env, fs, process};
+
+use regex::bytes::Regex;
+use serde_bytes::ByteBuf;
+
+use crate::database::{Reader, Writer};
+use crate::files::FileTree;
+use crate::package::{PathOrigin, StorePath};
+
+/// Returns a path in the temporary directory that is unique to this process and `name`.
+pub fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("nix-index-test-{}-{}", process::id(), name))
+}
+
+/// Returns the top-level `out` store path of the attribute `name`, with a fixed hash.
+pub fn store_path(name: &str) -> StorePath {
+    store_path_with_hash(name, "010yd8jls8w4vcnql4zhjbnyp2yay5pl")
+}
+
+/// Returns the top-level `out` store path of the attribute `name` with the given hash.
+pub fn store_path_with_hash(name: &str, hash: &str) -> StorePath {
+    let origin = PathOrigin {
+        attr: name.to_string(),
+        output: "out".to_string(),
+        toplevel: true,
+        system: None,
+    };
+    StorePath::parse(origin, &format!("/nix/store/{}-{}", hash, name)).expect("valid store path")
+}
+
+/// Returns the `out` store path of the top-level attribute `hello` for `system`, with the
+/// given hash.
+pub fn store_path_for_system(system: &str, hash: &str) -> StorePath {
+    let origin = PathOrigin {
+        system: Some(system.to_string()),
+        ..store_path("hello").origin().into_owned()
+    };
+    StorePath::parse(origin, &format!("/nix/store/{}-hello", hash)).expect("valid store path")
+}
+
+/// Returns a file tree with the given executables in `/bin`, each of size 10.
+pub fn bin_tree(programs: &[&str]) -> FileTree {
+    let bin = programs
+        .iter()
+        .map(|p| (ByteBuf::from(p.as_bytes()), FileTree::regular(10, true)))
+        .collect();
+    FileTree::directory(HashMap::from([(
+        ByteBuf::from("bin"),
+        FileTree::directory(bin),
+    )]))
+}
+
+/// Returns a symlink to `target`.
+pub fn link(target: &str) -> FileTree {
+    FileTree::symlink(ByteBuf::from(target))
+}
+
+/// Returns a package `hello` with symlinks inside of it, and a package `wrapper` whose
+/// symlinks lead into `hello`.
+pub fn symlink_packages() -> [(StorePath, FileTree); 2] {
+    let hello = FileTree::directory(HashMap::from([
+        (
+            ByteBuf::from("bin"),
+            FileTree::directory(HashMap::from([
+                (ByteBuf::from("hello"), FileTree::regular(10, true)),
+                (ByteBuf::from("relative"), link("../libexec/hello")),
+                (ByteBuf::from("dangling"), link("missing")),
+                (ByteBuf::from("loop"), link("loop")),
+                (ByteBuf::from("data"), link("../share/data")),
+            ])),
+        ),
+        (ByteBuf::from("libexec"), link("bin")),
+        (
+            ByteBuf::from("share"),
+            FileTree::directory(HashMap::from([(
+                ByteBuf::from("data"),
+                FileTree::regular(10, false),
+            )])),
+        ),
+    ]));
+    let root = store_path("hello").as_str().into_owned();
+    let wrapper = FileTree::directory(HashMap::from([(
+        ByteBuf::from("bin"),
+        FileTree::directory(HashMap::from([
+            (
+                ByteBuf::from("hello"),
+                link(&format!("{}/libexec/hello", root)),
+            ),
+            (
+                ByteBuf::from("absolute"),
+                link(&format!("{}/bin/hello", root)),
+            ),
+            (ByteBuf::from("data"), link(&format!("{}/bin/data", root))),
+            (ByteBuf::from("loop"), link(&format!("{}/bin/loop", root))),
+            (ByteBuf::from("opengl"), link("/run/opengl-driver/bin/x")),
+        ])),
+    )]));
+    [
+        (store_path("hello"), hello),
+        (
+            store_path_with_hash("wrapper", "7fzwp5z1nqy7rd6ygldrskm3dq6nhsmz"),
+            wrapper,
+        ),
+    ]
+}
+
+/// A database in a temporary file, which is removed again when this is dropped.
+pub struct TestDatabase {
+    path: PathBuf,
+}
+
+impl TestDatabase {
+    /// Creates a database with the given packages.
+    pub fn new(name: &str, packages: &[(StorePath, FileTree)]) -> TestDatabase {
+        TestDatabase::build(name, |writer| {
+            for (store_path, tree) in packages {
+                writer
+                    .add(store_path.clone(), tree.clone(), b"")
+                    .expect("package can be added");
+            }
+        })
+    }
+
+    /// Creates a database, letting `fill` configure the writer and add the packages.
+    pub fn build(name: &str, fill: impl FnOnce(&mut Writer)) -> TestDatabase {
+        TestDatabase::create(name, |path| {
+            let mut writer = Writer::create(path, 3).expect("database can be created");
+            fill(&mut writer);
+            writer.finish().expect("database can be finished");
+        })
+    }
+
+    /// Creates a database by letting `write` write the file at the given path, for databases
+    /// that the `Writer` does not write (such as ones in older formats).
+    pub fn create(name: &str, write: impl FnOnce(&Path)) -> TestDatabase {
+        let database = TestDatabase {
+            path: temp_path(name),
+        };
+        write(&database.path);
+        database
+    }
+
+    /// Returns the path of the database.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns a path for a cache of the database, which is removed together with it.
+    pub fn cache_path(&self) -> PathBuf {
+        self.path.with_extension("cache")
+    }
+
+    /// Opens the database.
+    pub fn reader(&self) -> Reader {
+        Reader::open(&self.path).expect("database can be opened")
+    }
+
+    /// Returns the names of the packages and the paths of all entries that match `pattern`,
+    /// sorted.
+    pub fn search(&self, pattern: &str) -> Vec<(String, Vec<u8>)> {
+        search(&self.path, pattern)
+    }
+}
+
+impl Drop for TestDatabase {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+        let _ = fs::remove_file(self.cache_path());
+    }
+}
+
+/// Returns the names of the packages and the paths of all entries of the database at `path`
+/// that match `pattern`, sorted.
+pub fn search(path: &Path, pattern: &str) -> Vec<(String, Vec<u8>)> {
+    let pattern = Regex::new(pattern).expect("valid regex");
+    let mut results = Reader::open(path)
+        .expect("database can be opened")
+        .query(&pattern)
+        .run()
+        .expect("query can be run")
+        .map(|r| {
+            let (pkg, entry) = r.expect("entry can be read");
+            (pkg.name().into_owned(), entry.path)
+        })
+        .collect::<Vec<_>>();
+    results.sort();
+    results
+}
@@ -1,8 +1,8 @@
 //! Small but reusable helper functions.
 use std::env;
-use std::fs::OpenOptions;
+use std::fs::{self, OpenOptions};
 use std::io::{self, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 /// Writes a file to the temp directory with a name that is made of the supplied
 /// base and a suffix if a file with that name already exists.
@@ -36,3 +36,122 @@ pub fn write_temp_file(base_name: &str, contents: &[u8]) -> Option<PathBuf> {
     }
     path
 }
+
+/// Returns the path of a file in the directory `nix-index` below `$XDG_RUNTIME_DIR` that
+/// belongs to the database at the given path, such as a cache or a socket. The file name is
+/// `PREFIX-CHECKSUM`, where the checksum is computed from the canonical path of the database.
+/// The directory is created if it does not exist.
+///
+/// An error is returned if `$XDG_RUNTIME_DIR` is not set.
+pub fn runtime_file(database: &Path, prefix: &str) -> io::Result<PathBuf> {
+    let database = fs::canonicalize(database)?;
+    let name = format!(
+        "{}-{:08x}",
+        prefix,
+        crc32fast::hash(database.as_os_str().as_encoded_bytes())
+    );
+    xdg::BaseDirectories::with_prefix("nix-index").place_runtime_file(name)
+}
+
+/// Formats a unix timestamp (in seconds) as a UTC date and time, such as `2017-03-14 09:26:53 UTC`.
+pub fn format_timestamp(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let secs = timestamp % 86400;
+
+    // Convert the number of days since the epoch to a civil date, using the algorithm from
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Returns the Nix system of the platform that this program was built for, such as
+/// `x86_64-linux` or `aarch64-darwin`.
+pub fn current_system() -> String {
+    let arch = match env::consts::ARCH {
+        "x86" => "i686",
+        "arm" => "armv7l",
+        arch => arch,
+    };
+    let os = match env::consts::OS {
+        "macos" => "darwin",
+        os => os,
+    };
+    format!("{}-{}", arch, os)
+}
+
+/// Parses a size in bytes with an optional unit, such as `512`, `10K`, `1.5MiB` or `2GB`.
+///
+/// The units `K`, `M`, `G` and `T` (also written as `KiB`, `MiB`, ...) are powers of 1024,
+/// while `kB`, `MB`, `GB` and `TB` are powers of 1000. Units are not case-sensitive.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let factor: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "ki" | "kib" => 1 << 10,
+        "m" | "mi" | "mib" => 1 << 20,
+        "g" | "gi" | "gib" => 1 << 30,
+        "t" | "ti" | "tib" => 1 << 40,
+        "kb" => 1_000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        "tb" => 1_000_000_000_000,
+        _ => return Err(format!("unknown unit '{}' in size '{}'", unit.trim(), s)),
+    };
+    if let Ok(number) = number.parse::<u64>() {
+        return number
+            .checked_mul(factor)
+            .ok_or_else(|| format!("size '{}' is too large", s));
+    }
+    match number.parse::<f64>() {
+        Ok(number) if number.is_finite() => Ok((number * factor as f64).round() as u64),
+        _ => Err(format!("invalid size '{}'", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size(" 512b "), Ok(512));
+        assert_eq!(parse_size("10K"), Ok(10 * 1024));
+        assert_eq!(parse_size("10 KiB"), Ok(10 * 1024));
+        assert_eq!(parse_size("1.5MiB"), Ok(1536 * 1024));
+        assert_eq!(parse_size("3T"), Ok(3 << 40));
+        assert_eq!(parse_size("2gb"), Ok(2_000_000_000));
+        assert_eq!(parse_size("1kB"), Ok(1_000));
+        assert_eq!(
+            parse_size("1X"),
+            Err("unknown unit 'X' in size '1X'".to_string())
+        );
+        assert_eq!(parse_size("M"), Err("invalid size 'M'".to_string()));
+        assert_eq!(parse_size("1.2.3"), Err("invalid size '1.2.3'".to_string()));
+        assert_eq!(
+            parse_size("20000000T"),
+            Err("size '20000000T' is too large".to_string())
+        );
+    }
+}
@@ -183,6 +183,14 @@ impl<K: Hash + Eq + 'static, V: 'static> WorkSet<K, V> {
             state: Rc::new(RefCell::new(shared)),
         }
     }
+
+    /// Marks the given keys as already processed, so that no work will be added for them
+    /// in the future.
+    ///
+    /// This does not remove work that is already in the queue.
+    pub fn mark_seen<I: IntoIterator<Item = K>>(&self, keys: I) {
+        self.state.borrow_mut().seen.extend(keys);
+    }
 }
 
 impl<K: Hash + Eq + 'static, V: 'static> FromIterator<(K, V)> for WorkSet<K, V> {